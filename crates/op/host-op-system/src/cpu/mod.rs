@@ -12,6 +12,8 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+mod utilization;
+
 use std::time::Duration;
 
 use psh_system::cpu::{
@@ -29,6 +31,8 @@ use crate::{
     },
 };
 
+pub use utilization::add_to_linker as add_utilization_to_linker;
+
 impl From<&HostCpuMask> for GuestCpuMask {
     fn from(value: &HostCpuMask) -> Self {
         Self {
@@ -0,0 +1,75 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::time::Duration;
+
+use psh_system::cpu::{
+    CpuUtilization as HostCpuUtilization, CpuUtilizations as HostCpuUtilizations,
+};
+use wasmtime::component::Linker;
+
+use self::profiling::cpu_utilization::cpu_utilization::{
+    self, CpuUtilization as GuestCpuUtilization, CpuUtilizations as GuestCpuUtilizations,
+};
+use crate::SysCtx;
+
+wasmtime::component::bindgen!({
+    // Vendored like profiling:distribution until the system world of
+    // psh-sdk-wit has it, see its README for what guests need meanwhile.
+    path: "../../../wit/cpu-utilization",
+    world: "imports",
+});
+
+impl From<HostCpuUtilization> for GuestCpuUtilization {
+    fn from(value: HostCpuUtilization) -> Self {
+        Self {
+            user: value.user,
+            nice: value.nice,
+            system: value.system,
+            idle: value.idle,
+            iowait: value.iowait,
+            irq: value.irq,
+            softirq: value.softirq,
+            steal: value.steal,
+            guest: value.guest,
+            guest_nice: value.guest_nice,
+        }
+    }
+}
+
+impl From<HostCpuUtilizations> for GuestCpuUtilizations {
+    fn from(value: HostCpuUtilizations) -> Self {
+        Self {
+            total: value.total.into(),
+            per_cpu: value.per_cpu.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl cpu_utilization::Host for SysCtx {
+    fn utilization(&mut self, interval_ms: u64) -> Result<GuestCpuUtilizations, String> {
+        self.cpu
+            .utilization(Some(Duration::from_millis(interval_ms)))
+            .map_err(|err| err.to_string())?
+            .map(Into::into)
+            .ok_or_else(|| "No CPU time passed since boot".to_string())
+    }
+}
+
+pub fn add_to_linker<T>(
+    l: &mut Linker<T>,
+    f: impl (Fn(&mut T) -> &mut SysCtx) + Copy + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    cpu_utilization::add_to_linker(l, f)
+}
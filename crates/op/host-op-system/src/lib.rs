@@ -64,9 +64,76 @@ wasmtime::component::bindgen!({
     ],
 });
 
+/// Interfaces of the system world that may be linked for a guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemInterfaces {
+    pub os: bool,
+    pub cpu: bool,
+    pub disk: bool,
+    pub interrupt: bool,
+    pub memory: bool,
+    pub network: bool,
+    pub process: bool,
+    /// Reading the environment of other processes, requires `process`.
+    pub process_environ: bool,
+    pub rps: bool,
+    pub vmstat: bool,
+}
+
+impl SystemInterfaces {
+    pub const fn all() -> Self {
+        Self {
+            os: true,
+            cpu: true,
+            disk: true,
+            interrupt: true,
+            memory: true,
+            network: true,
+            process: true,
+            process_environ: true,
+            rps: true,
+            vmstat: true,
+        }
+    }
+
+    pub const fn none() -> Self {
+        Self {
+            os: false,
+            cpu: false,
+            disk: false,
+            interrupt: false,
+            memory: false,
+            network: false,
+            process: false,
+            process_environ: false,
+            rps: false,
+            vmstat: false,
+        }
+    }
+
+    pub const fn is_empty(&self) -> bool {
+        !(self.os
+            || self.cpu
+            || self.disk
+            || self.interrupt
+            || self.memory
+            || self.network
+            || self.process
+            || self.rps
+            || self.vmstat)
+    }
+}
+
+impl Default for SystemInterfaces {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Default)]
 pub struct SysCtx {
+    interfaces: SystemInterfaces,
     table: ResourceTable,
     system: System,
     os: OsHandle,
@@ -80,9 +147,58 @@ pub struct SysCtx {
     vmstat: VmstatHandle,
 }
 
+impl SysCtx {
+    pub fn new(interfaces: SystemInterfaces) -> Self {
+        Self {
+            interfaces,
+            ..Default::default()
+        }
+    }
+}
+
 pub fn add_to_linker<T>(
     l: &mut Linker<T>,
     f: impl (Fn(&mut T) -> &mut SysCtx) + Copy + Send + Sync + 'static,
 ) -> anyhow::Result<()> {
-    crate::Imports::add_to_linker(l, f)
+    crate::Imports::add_to_linker(l, f)?;
+    crate::cpu::add_utilization_to_linker(l, f)
+}
+
+/// Only link the granted interfaces, a guest importing any other one fails to instantiate.
+pub fn add_to_linker_with<T>(
+    l: &mut Linker<T>,
+    f: impl (Fn(&mut T) -> &mut SysCtx) + Copy + Send + Sync + 'static,
+    interfaces: &SystemInterfaces,
+) -> anyhow::Result<()> {
+    use profiling::system::{cpu, disk, interrupt, memory, network, os, process, rps, vmstat};
+
+    if interfaces.os {
+        os::add_to_linker(l, f)?;
+    }
+    if interfaces.cpu {
+        cpu::add_to_linker(l, f)?;
+        crate::cpu::add_utilization_to_linker(l, f)?;
+    }
+    if interfaces.disk {
+        disk::add_to_linker(l, f)?;
+    }
+    if interfaces.interrupt {
+        interrupt::add_to_linker(l, f)?;
+    }
+    if interfaces.memory {
+        memory::add_to_linker(l, f)?;
+    }
+    if interfaces.network {
+        network::add_to_linker(l, f)?;
+    }
+    if interfaces.process {
+        process::add_to_linker(l, f)?;
+    }
+    if interfaces.rps {
+        rps::add_to_linker(l, f)?;
+    }
+    if interfaces.vmstat {
+        vmstat::add_to_linker(l, f)?;
+    }
+    Ok(())
 }
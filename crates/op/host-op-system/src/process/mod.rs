@@ -96,6 +96,11 @@ impl process::HostProcess for SysCtx {
         &mut self,
         self_: Resource<Arc<Process>>,
     ) -> wasmtime::Result<Result<Vec<(String, String)>, String>> {
+        if !self.interfaces.process_environ {
+            return Ok(Err(
+                "Permission denied: process environ is not granted".to_string()
+            ));
+        }
         let proc = self.table.get(&self_)?;
         Ok(proc
             .environ()
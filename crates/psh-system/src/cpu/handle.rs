@@ -12,12 +12,18 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use std::{sync::LazyLock, time::Duration};
+use std::{
+    sync::{Arc, LazyLock, Mutex},
+    time::Duration,
+};
 
 use procfs::CurrentSI;
 
-use super::{CpuInfo, CpuStats, raw::parse_cpuinfo};
-use crate::{error::Result, utils::Handle};
+use super::{CpuInfo, CpuStats, CpuUtilizations, raw::parse_cpuinfo};
+use crate::{
+    error::{Error, Result},
+    utils::Handle,
+};
 
 static INFO_GLOBAL: LazyLock<Handle<CpuInfo>> =
     LazyLock::new(|| Handle::new(|| parse_cpuinfo!().map_err(Into::into)));
@@ -30,10 +36,15 @@ static STAT_GLOBAL: LazyLock<Handle<CpuStats>> = LazyLock::new(|| {
     })
 });
 
+/// Sample and utilization of the previous [`CpuHandle::utilization`] call.
+type Previous = Option<(CpuStats, CpuUtilizations)>;
+
 #[derive(Debug, Clone)]
 pub struct CpuHandle {
     info: Handle<CpuInfo>,
     stat: Handle<CpuStats>,
+    /// shared by clones, other handles measure on their own
+    prev: Arc<Mutex<Previous>>,
 }
 
 impl Default for CpuHandle {
@@ -41,6 +52,7 @@ impl Default for CpuHandle {
         Self {
             info: INFO_GLOBAL.clone(),
             stat: STAT_GLOBAL.clone(),
+            prev: Arc::default(),
         }
     }
 }
@@ -57,4 +69,23 @@ impl CpuHandle {
     pub fn stat(&self, interval: Option<Duration>) -> Result<CpuStats> {
         self.stat.get(interval)
     }
+
+    /// Utilization since the previous call on this handle, or since boot the
+    /// first time. The stat is read again once it is `interval` old, until
+    /// then the previous utilization is returned again. None only if no tick
+    /// passed since boot.
+    pub fn utilization(&self, interval: Option<Duration>) -> Result<Option<CpuUtilizations>> {
+        let cur = self.stat(interval)?;
+        let Ok(mut prev) = self.prev.lock() else {
+            return Err(Error::Sync);
+        };
+        let last = prev.as_ref().map(|(_, utilization)| utilization);
+        let Some(utilization) = CpuUtilizations::between(prev.as_ref().map(|it| &it.0), &cur, last)
+        else {
+            return Ok(last.cloned());
+        };
+        *prev = Some((cur, utilization.clone()));
+        drop(prev);
+        Ok(Some(utilization))
+    }
 }
@@ -18,10 +18,12 @@ use crate::error::{Error, Result};
 
 pub(crate) mod handle;
 mod raw;
+mod utilization;
 
 pub use handle::CpuHandle;
 pub use procfs::CpuTime;
 use procfs::KernelStats;
+pub use utilization::{CpuUtilization, CpuUtilizations};
 
 // use Vec<bool> to represent CpuMask but wrap it in a tuple struct to make it a distinct type
 #[derive(Debug, PartialEq, Eq, Clone)]
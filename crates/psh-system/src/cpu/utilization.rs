@@ -0,0 +1,135 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use procfs::CpuTime;
+
+use super::CpuStats;
+
+/// Share of the time a CPU spent in each state between two samples, each one
+/// within [0, 1]. Guest time is also counted in user and nice, as the kernel
+/// does, states older kernels do not report are 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CpuUtilization {
+    pub user: f64,
+    pub nice: f64,
+    pub system: f64,
+    pub idle: f64,
+    pub iowait: f64,
+    pub irq: f64,
+    pub softirq: f64,
+    pub steal: f64,
+    pub guest: f64,
+    pub guest_nice: f64,
+}
+
+/// Ticks of each state, in the order of the fields of [`CpuUtilization`].
+type Ticks = [u64; 10];
+
+fn ticks(t: &CpuTime) -> Ticks {
+    [
+        t.user,
+        t.nice,
+        t.system,
+        t.idle,
+        t.iowait.unwrap_or(0),
+        t.irq.unwrap_or(0),
+        t.softirq.unwrap_or(0),
+        t.steal.unwrap_or(0),
+        t.guest.unwrap_or(0),
+        t.guest_nice.unwrap_or(0),
+    ]
+}
+
+impl CpuUtilization {
+    /// Utilization between the `prev` and `cur` samples of the same CPU,
+    /// since boot without `prev`. None if no tick passed in between.
+    pub fn between(prev: Option<&CpuTime>, cur: &CpuTime) -> Option<Self> {
+        Self::from_ticks(prev.map_or([0; 10], ticks), ticks(cur))
+    }
+
+    fn from_ticks(prev: Ticks, cur: Ticks) -> Option<Self> {
+        // Counters may go backwards, iowait on idle CPUs notably.
+        let d: [f64; 10] = std::array::from_fn(|i| cur[i].saturating_sub(prev[i]) as f64);
+        // Guest time is part of user and nice already.
+        let total: f64 = d[..8].iter().sum();
+        if total == 0.0 {
+            return None;
+        }
+
+        Some(Self {
+            user: d[0] / total,
+            nice: d[1] / total,
+            system: d[2] / total,
+            idle: d[3] / total,
+            iowait: d[4] / total,
+            irq: d[5] / total,
+            softirq: d[6] / total,
+            steal: d[7] / total,
+            guest: d[8] / total,
+            guest_nice: d[9] / total,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuUtilizations {
+    pub total: CpuUtilization,
+    pub per_cpu: Vec<CpuUtilization>,
+}
+
+impl CpuUtilizations {
+    /// Utilization between two samples, since boot without `prev`. None if
+    /// no tick passed in between. CPUs that came online in between are
+    /// measured since boot as well, those without a tick keep their `last`
+    /// value. A CPU without any tick since boot, and only that, is all 0.
+    pub fn between(prev: Option<&CpuStats>, cur: &CpuStats, last: Option<&Self>) -> Option<Self> {
+        let total = CpuUtilization::between(prev.map(|it| &it.total), &cur.total)?;
+        let per_cpu = cur
+            .per_cpu
+            .iter()
+            .enumerate()
+            .map(|(i, t)| {
+                CpuUtilization::between(prev.and_then(|it| it.per_cpu.get(i)), t)
+                    .or_else(|| last.and_then(|it| it.per_cpu.get(i).copied()))
+                    .or_else(|| CpuUtilization::between(None, t))
+                    .unwrap_or_default()
+            })
+            .collect();
+        Some(Self { total, per_cpu })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CpuUtilization;
+
+    #[test]
+    fn test_from_ticks() {
+        let prev = [100, 0, 50, 1000, 10, 0, 0, 0, 0, 0];
+        let cur = [130, 0, 60, 1055, 15, 0, 0, 0, 20, 0];
+        let u = CpuUtilization::from_ticks(prev, cur).unwrap();
+        assert_eq!(u.user, 0.3);
+        assert_eq!(u.system, 0.1);
+        assert_eq!(u.idle, 0.55);
+        assert_eq!(u.iowait, 0.05);
+        assert_eq!(u.guest, 0.2);
+
+        // no time passed
+        assert_eq!(CpuUtilization::from_ticks(cur, cur), None);
+        // iowait went backwards
+        let u = CpuUtilization::from_ticks(prev, [100, 0, 50, 1010, 5, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(u.idle, 1.0);
+        assert_eq!(u.iowait, 0.0);
+    }
+}
@@ -12,13 +12,16 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Config file
     /// └╴Will be generated if it does not exist
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     #[arg(value_name = "PATH")]
     #[arg(default_value = "/etc/psh/config.toml")]
     #[arg(verbatim_doc_comment)]
@@ -46,3 +49,33 @@ pub struct Args {
     #[arg(verbatim_doc_comment)]
     pub wasm_with_args: Option<Vec<String>>,
 }
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Control the running daemon through its control socket
+    #[command(subcommand)]
+    Ctl(CtlCommand),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlCommand {
+    /// List queued and running tasks
+    Tasks,
+    /// Run a local WASM binary
+    Submit {
+        /// How long the task may run, in seconds
+        #[arg(long)]
+        time_slice: Option<u64>,
+        /// WASM binary followed with arguments
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        wasm_with_args: Vec<String>,
+    },
+    /// Cancel a queued or running task
+    Cancel { id: String },
+    /// Show task slots and data export queues
+    Status,
+    /// Reload local tasks from the config file
+    Reload,
+    /// Show the most recent output of a running task
+    Logs { id: String },
+}
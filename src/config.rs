@@ -18,6 +18,12 @@ use anyhow::Result;
 use serde::Deserialize;
 use std::time::Duration;
 
+use crate::otlp::{CollectorsConfig, GuestMetricsConfig, MetricNaming, PrometheusConfig};
+use crate::runtime::{
+    Capabilities, ComponentCacheConfig, FileSinkConfig, InfluxDbConfig, OverflowPolicy,
+    ReservedTagPolicy, SignatureConfig, SpoolConfig, TaskLimits, TaskOutputConfig,
+};
+
 const TEMPLATE: &str = include_str!("../doc/config.toml");
 
 #[derive(Deserialize)]
@@ -33,6 +39,11 @@ pub struct DaemonConfig {
     pub stderr: String,
     pub workdir: String,
     pub wasm: DaemonWasmConfig,
+    /// unix socket of `psh ctl`, `/run/psh.sock` by default
+    pub control_socket: Option<String>,
+    /// run on their own schedule, without the RPC server
+    #[serde(default)]
+    pub tasks: Vec<LocalTaskConfig>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -42,11 +53,31 @@ pub struct DaemonWasmConfig {
     pub args: Vec<String>,
 }
 
+#[derive(Clone, Deserialize)]
+pub struct LocalTaskConfig {
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// in seconds, exclusive with `cron`
+    pub interval: Option<u64>,
+    /// 5 fields cron expression in local time, exclusive with `interval`
+    pub cron: Option<String>,
+    /// in seconds, how long a single run may last
+    pub time_slice: u64,
+    /// the runtime wide capabilities if unset
+    pub capabilities: Option<Capabilities>,
+    #[serde(default)]
+    pub limits: TaskLimits,
+}
+
 #[derive(Deserialize)]
 pub struct RemoteConfig {
     pub token: String,
     pub rpc: RpcConfig,
     pub otlp: OtlpConfig,
+    /// serve the host metrics to Prometheus scrapes if set
+    pub prometheus: Option<PrometheusConfig>,
 }
 
 #[derive(Deserialize)]
@@ -60,6 +91,23 @@ pub struct RpcConfig {
     pub max_retries: Option<u32>,
     #[serde(deserialize_with = "deserialize_duration")]
     pub base_delay: Option<Duration>,
+    /// max number of tasks running at the same time, 1 by default
+    pub max_concurrent_tasks: Option<usize>,
+    /// default resource limits of tasks, can be overridden per task
+    #[serde(default)]
+    pub limits: TaskLimits,
+    /// default capabilities of tasks, everything is granted if unset
+    #[serde(default)]
+    pub capabilities: Capabilities,
+    /// only run components signed by a trusted key if set
+    pub signature: Option<SignatureConfig>,
+    /// keep compiled components on disk if set
+    pub component_cache: Option<ComponentCacheConfig>,
+    /// what happens to stdout and stderr of tasks
+    #[serde(default)]
+    pub output: TaskOutputConfig,
+    /// requests are sent uncompressed if unset
+    pub compression: Option<CompressionConfig>,
 }
 
 fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
@@ -76,12 +124,55 @@ pub struct OtlpConfig {
     pub addr: String,
     /// in seconds
     pub interval: u64,
+    /// re-emit what tasks export along with the host metrics
+    #[serde(default)]
+    pub guest_metrics: GuestMetricsConfig,
+    /// which host gauges are collected and how, all of them by default
+    #[serde(default)]
+    pub collectors: CollectorsConfig,
+    /// how host metrics are named, psh by default
+    #[serde(default)]
+    pub naming: MetricNaming,
 }
 
 #[derive(Deserialize)]
 pub struct DataExportConfig {
+    /// in bytes, the most a task may have waiting to be exported
     pub buf_size: usize,
     pub buf_watermark: usize,
+    /// what happens to data exported while the buffer is full, the buffer is
+    /// unbounded if unset
+    pub overflow: Option<OverflowPolicy>,
+    /// in milliseconds, buffered data waits at most this long if set
+    pub flush_interval: Option<u64>,
+    /// keep the data of local tasks in files if set
+    pub file: Option<FileSinkConfig>,
+    /// keep batches the server did not take on disk and retry them if set
+    pub spool: Option<SpoolConfig>,
+    /// also write line protocol to InfluxDB if set
+    pub influxdb: Option<InfluxDbConfig>,
+    /// distinct series each task may export, 10000 by default
+    pub max_series: Option<usize>,
+    /// what happens to guest tags named like the ones psh adds
+    #[serde(default)]
+    pub reserved_tags: ReservedTagPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CompressionConfig {
+    pub algorithm: Compression,
+    /// in bytes, the exported files of a request are compressed on their own
+    /// if one is at least this large
+    pub min_file_size: Option<usize>,
+    /// level of file compression, the algorithm's default if unset
+    pub level: Option<i32>,
 }
 
 pub fn read_or_gen<P>(path: P) -> Result<Config>
@@ -93,7 +184,11 @@ where
         fs::write(path, TEMPLATE)?;
     }
     let cfg = fs::read_to_string(path)?;
-    let cfg: Config = toml::from_str(&cfg)?;
+    parse(&cfg)
+}
+
+pub fn parse(cfg: &str) -> Result<Config> {
+    let cfg: Config = toml::from_str(cfg)?;
     Ok(cfg)
 }
 
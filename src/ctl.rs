@@ -0,0 +1,98 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::fs;
+
+use anyhow::{Context, Result, bail};
+
+use crate::{
+    args::CtlCommand,
+    services::control::{Request, Response, request},
+};
+
+pub fn run(socket: &str, cmd: CtlCommand) -> Result<()> {
+    let req = match cmd {
+        CtlCommand::Tasks => Request::Tasks,
+        CtlCommand::Submit {
+            time_slice,
+            mut wasm_with_args,
+        } => {
+            // The daemon may not share our working directory.
+            let path = fs::canonicalize(&wasm_with_args[0])
+                .with_context(|| format!("Failed to find {}", wasm_with_args[0]))?;
+            let args = wasm_with_args.split_off(1);
+            Request::Submit {
+                path: path.to_string_lossy().to_string(),
+                args,
+                time_slice,
+            }
+        }
+        CtlCommand::Cancel { id } => Request::Cancel { id },
+        CtlCommand::Status => Request::Status,
+        CtlCommand::Reload => Request::Reload,
+        CtlCommand::Logs { id } => Request::Logs { id },
+    };
+
+    match request(socket, &req)? {
+        Response::Tasks { tasks } => {
+            for (id, phase) in tasks {
+                println!("{:<12} {}", format!("{:?}", phase).to_lowercase(), id);
+            }
+        }
+        Response::Submitted { id } => println!("{}", id),
+        Response::Cancelled => {}
+        Response::Status(status) => {
+            println!("slots: {}/{} free", status.free_slots, status.concurrency);
+            println!(
+                "tasks: {} queued, {} running",
+                status.queued, status.running
+            );
+            println!("pending reports: {}", status.pending_reports);
+            for exporter in status.exporters {
+                println!(
+                    "exporter {}: {} items, {} bytes queued",
+                    exporter.task_id, exporter.queued_items, exporter.queued_bytes
+                );
+            }
+            for spool in status.spools {
+                println!(
+                    "spool {}: {} batches, {} bytes queued; {} spooled, {} retried, {} dropped, {} rejected",
+                    spool.sink,
+                    spool.queued_batches,
+                    spool.queued_bytes,
+                    spool.spooled,
+                    spool.retried,
+                    spool.dropped,
+                    spool.rejected
+                );
+            }
+        }
+        Response::Reloaded { local_tasks } => {
+            println!(
+                "{} local tasks reloaded, other settings need a restart",
+                local_tasks
+            );
+        }
+        Response::Logs { lines, dropped } => {
+            if dropped > 0 {
+                println!("... {} older lines dropped", dropped);
+            }
+            for line in lines {
+                println!("[{}] {}", line.stream.name(), line.line);
+            }
+        }
+        Response::Error { message } => bail!(message),
+    }
+    Ok(())
+}
@@ -14,28 +14,33 @@
 
 mod args;
 mod config;
+mod ctl;
 mod daemon;
 mod log;
 mod otlp;
 mod runtime;
 mod services;
 
-use std::{fs, sync::LazyLock, thread, time::Duration};
+use std::{fs, path::Path, sync::LazyLock, thread, time::Duration};
 
 use anyhow::{Error, Result, bail};
-use args::Args;
+use args::{Args, Command};
 use chrono::{TimeZone, Utc};
 use clap::Parser;
-use config::RemoteConfig;
+use config::{LocalTaskConfig, RemoteConfig};
 use daemon::{get_daemon_wasm_args, spawn_daemon};
 use log::log_init;
 use mimalloc::MiMalloc;
 use nix::unistd::geteuid;
 use opentelemetry_otlp::ExportConfig;
 use psh_proto::HeartbeatReq;
-use runtime::{Task, TaskRuntime};
-use services::rpc::RpcClient;
-use tokio::{runtime::Runtime, try_join};
+use runtime::{JOURNAL_DIR, Journal, Task, TaskLimits, TaskOrigin, TaskRuntime};
+use services::{
+    control::{ControlServer, DEFAULT_SOCKET},
+    rpc::RpcClient,
+    scheduler::run_local_tasks,
+};
+use tokio::{runtime::Runtime, sync::watch, try_join};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = mimalloc::MiMalloc;
@@ -50,8 +55,22 @@ fn main() -> Result<()> {
         bail!("Insufficient privileges. Please run psh with root permissions.");
     }
 
-    let args = Args::parse();
+    let mut args = Args::parse();
     let cfg = config::read_or_gen(args.config.clone())?;
+    let control_socket = cfg
+        .daemon
+        .control_socket
+        .clone()
+        .unwrap_or_else(|| DEFAULT_SOCKET.to_string());
+
+    if let Some(Command::Ctl(cmd)) = args.command.take() {
+        return ctl::run(&control_socket, cmd);
+    }
+    // A WASM given on the command line runs once, there is no daemon to control.
+    let control = args
+        .wasm_with_args
+        .is_none()
+        .then(|| (control_socket, args.config.clone()));
 
     let wasm_with_args = match args {
         Args {
@@ -83,7 +102,11 @@ fn main() -> Result<()> {
         }
     };
 
-    let task_rt = TaskRuntime::new()?;
+    let mut task_rt = TaskRuntime::new(&cfg.remote.rpc)?;
+    if cfg.remote.rpc.enable {
+        let journal = Journal::open(Path::new(&cfg.daemon.workdir).join(JOURNAL_DIR))?;
+        task_rt.use_journal(journal)?;
+    }
 
     if let Some(args) = wasm_with_args {
         let task = Task {
@@ -91,12 +114,15 @@ fn main() -> Result<()> {
             wasm_component: fs::read(&args[0])?,
             wasm_component_args: args,
             end_time: Utc.with_ymd_and_hms(3000, 1, 1, 1, 1, 1).unwrap(),
+            limits: TaskLimits::default(),
+            capabilities: None,
+            origin: TaskOrigin::Local,
         };
         task_rt.schedule(task)?;
     };
 
     thread::spawn(move || -> Result<()> {
-        let tasks = async_tasks(cfg.remote, task_rt);
+        let tasks = async_tasks(cfg.remote, cfg.daemon.tasks, control, task_rt);
         TOKIO_RUNTIME.block_on(tasks)?;
         Ok(())
     })
@@ -106,63 +132,112 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-async fn async_tasks(remote_cfg: RemoteConfig, mut task_rt: TaskRuntime) -> Result<()> {
-    let token_cloned = remote_cfg.token.clone();
-    let rpc_task = async move {
-        if !remote_cfg.rpc.enable {
-            let handle = task_rt.spawn(
-                None,
-                remote_cfg.rpc.data_export.buf_size,
-                remote_cfg.rpc.data_export.buf_watermark,
-                "unknown".to_string(),
-            )?;
-            drop(task_rt);
-            handle.join().expect("TaskRuntime has panicked");
-            return Ok(());
-        }
-
-        let duration = Duration::from_secs(remote_cfg.rpc.heartbeat_interval);
-        let mut client = RpcClient::new(&remote_cfg.rpc, token_cloned).await?;
-
-        let instance_id = match fs::read_to_string(&remote_cfg.rpc.instance_id_file).ok() {
+async fn async_tasks(
+    remote_cfg: RemoteConfig,
+    local_tasks: Vec<LocalTaskConfig>,
+    control: Option<(String, String)>,
+    mut task_rt: TaskRuntime,
+) -> Result<()> {
+    let rpc_cfg = &remote_cfg.rpc;
+    let (client, instance_id) = if rpc_cfg.enable {
+        let mut client = RpcClient::new(rpc_cfg, remote_cfg.token.clone()).await?;
+        let instance_id = match fs::read_to_string(&rpc_cfg.instance_id_file).ok() {
             Some(s) => s,
             None => {
                 let instance_id = client.new_instance_id().await?;
-                fs::write(&remote_cfg.rpc.instance_id_file, &instance_id)?;
+                fs::write(&rpc_cfg.instance_id_file, &instance_id)?;
                 instance_id
             }
         };
+        (Some(client), instance_id)
+    } else {
+        (None, "unknown".to_string())
+    };
 
-        task_rt.spawn(
-            Some(client.clone()),
-            remote_cfg.rpc.data_export.buf_size,
-            remote_cfg.rpc.data_export.buf_watermark,
-            instance_id.clone(),
+    let prometheus = remote_cfg.prometheus.as_ref().filter(|it| it.enable);
+    let scrape = prometheus.map(|_| otlp::ScrapeReader::default());
+    // Built before the workers, which may report what tasks export through it.
+    let otlp = if remote_cfg.otlp.enable || scrape.is_some() {
+        let export_conf = remote_cfg.otlp.enable.then(|| ExportConfig {
+            endpoint: Some(remote_cfg.otlp.addr.clone()),
+            ..Default::default()
+        });
+        let otlp = otlp::Otlp::new(
+            remote_cfg.token.clone(),
+            Duration::from_secs(remote_cfg.otlp.interval),
+            remote_cfg.otlp.collectors.clone(),
+            remote_cfg.otlp.naming,
+            export_conf,
+            scrape.clone(),
         )?;
+        if remote_cfg.otlp.guest_metrics.enable {
+            task_rt.bridge_metrics(otlp.guest_metrics(&remote_cfg.otlp.guest_metrics));
+        }
+        Some(otlp)
+    } else {
+        None
+    };
+
+    let handle = task_rt.spawn(
+        client.clone(),
+        rpc_cfg.data_export.buf_size,
+        rpc_cfg.data_export.buf_watermark,
+        instance_id.clone(),
+    )?;
+
+    let rpc_task = async {
+        let Some(mut client) = client else {
+            return Ok(());
+        };
+        let duration = Duration::from_secs(rpc_cfg.heartbeat_interval);
         client.send_host_info(instance_id.clone()).await?;
         loop {
-            let idle = task_rt.is_idle();
-            if idle {
-                if let Some(mut task) = client.get_task(instance_id.clone()).await? {
-                    let task_id = task
-                        .id
-                        .as_ref()
-                        .map(|it| it.to_string())
-                        .expect("No task id provided");
-                    task.wasm_component_args.insert(0, task_id);
-                    task_rt.schedule(task)?
+            // Polled even while busy, cancelling frees slots.
+            match client.cancelled_tasks(instance_id.clone()).await {
+                Ok(task_ids) => {
+                    for task_id in task_ids {
+                        if !task_rt.cancel(&task_id) {
+                            tracing::warn!("Cannot cancel unknown task {}", task_id);
+                        }
+                    }
                 }
+                // Not fatal, tasks are still fetched and reported.
+                Err(e) => tracing::warn!("Failed to get cancelled tasks: {:#}", e),
+            }
+            while task_rt.free_slots() > 0 {
+                let Some(mut task) = client.get_task(instance_id.clone()).await? else {
+                    break;
+                };
+                let task_id = task
+                    .id
+                    .as_ref()
+                    .map(|it| it.to_string())
+                    .expect("No task id provided");
+                task.wasm_component_args.insert(0, task_id);
+                task_rt.schedule(task)?;
             }
 
+            let free_slots = task_rt.free_slots();
+            tracing::trace!("{} free task slots", free_slots);
             client
                 .heartbeat(HeartbeatReq {
                     instance_id: instance_id.clone(),
-                    idle,
+                    idle: free_slots > 0,
                 })
                 .await?;
 
-            if let Some(id) = task_rt.finished_task_id() {
-                let _ = client.task_done(id).await;
+            while let Some((id, outcome)) = task_rt.finished_task() {
+                match client
+                    .task_finished(&instance_id, id.clone(), &outcome)
+                    .await
+                {
+                    Ok(()) => task_rt.reported(&id),
+                    Err(e) => {
+                        tracing::warn!("Failed to report outcome of task {}: {:#}", id, e);
+                        task_rt.defer_report(id, outcome);
+                        break;
+                    }
+                }
             }
 
             tokio::time::sleep(duration).await;
@@ -172,24 +247,44 @@ async fn async_tasks(remote_cfg: RemoteConfig, mut task_rt: TaskRuntime) -> Resu
     };
 
     let otlp_task = async {
-        if !remote_cfg.otlp.enable {
+        let Some(otlp) = &otlp else {
             return Ok(());
-        }
-        let export_conf = ExportConfig {
-            endpoint: Some(remote_cfg.otlp.addr),
-            ..Default::default()
         };
-        let otlp = otlp::Otlp::new(
-            remote_cfg.token,
-            Duration::from_secs(remote_cfg.otlp.interval),
-            export_conf,
-        )?;
-
         otlp.otlp_tasks().await;
         Ok::<(), Error>(())
     };
 
-    try_join!(rpc_task, otlp_task)?;
+    let prometheus_task = async {
+        let (Some(cfg), Some(scrape)) = (prometheus, scrape) else {
+            return Ok(());
+        };
+        otlp::prometheus::serve(cfg, scrape).await
+    };
+
+    let (local_tasks_tx, local_tasks_rx) = watch::channel(local_tasks);
+    let local_task = run_local_tasks(local_tasks_rx, &task_rt);
+
+    let control_task = async {
+        let Some((socket, config_path)) = control else {
+            drop(local_tasks_tx);
+            return Ok(());
+        };
+        ControlServer::new(&task_rt, config_path, local_tasks_tx)
+            .serve(&socket)
+            .await
+    };
+
+    try_join!(
+        rpc_task,
+        local_task,
+        control_task,
+        otlp_task,
+        prometheus_task
+    )?;
+
+    // Only reached without RPC, wait for the scheduled tasks to finish.
+    drop(task_rt);
+    tokio::task::spawn_blocking(move || handle.join().expect("TaskRuntime has panicked")).await?;
 
     Ok(())
 }
@@ -0,0 +1,280 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::BTreeSet,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+/// One gauge family.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CollectorConfig {
+    pub enable: bool,
+    /// in seconds, how often the family reads the system, the OTLP interval if unset
+    #[serde(deserialize_with = "deserialize_interval")]
+    pub interval: Option<u64>,
+    /// patterns of what to report, everything if empty
+    pub include: Vec<String>,
+    /// patterns of what not to report, applied after `include`
+    pub exclude: Vec<String>,
+}
+
+impl Default for CollectorConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            interval: None,
+            include: vec![],
+            exclude: vec![],
+        }
+    }
+}
+
+fn deserialize_interval<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match u64::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom(
+            "interval must be at least 1 second",
+        )),
+        seconds => Ok(Some(seconds)),
+    }
+}
+
+impl CollectorConfig {
+    pub fn interval(&self, default: Duration) -> Duration {
+        self.interval.map_or(default, Duration::from_secs)
+    }
+
+    pub fn filter(&self) -> Filter {
+        Filter {
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+        }
+    }
+}
+
+/// Which gauge families are registered and how, by the name of the family.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CollectorsConfig {
+    /// filtered on cpu ids
+    pub cpu: CollectorConfig,
+    /// filtered on stat names
+    pub memory: CollectorConfig,
+    /// filtered on disk names
+    pub disk: CollectorConfig,
+    /// filtered on interface names
+    pub network: CollectorConfig,
+    /// filtered on interrupt names, such as `LOC` or `24`, and descriptions
+    pub interrupt: CollectorConfig,
+    /// filtered on device names
+    pub rps: CollectorConfig,
+    /// filtered on stat names
+    pub vmstat: CollectorConfig,
+    /// filtered on device uuids and names
+    pub gpu: CollectorConfig,
+}
+
+impl CollectorsConfig {
+    const fn all(&self) -> [&CollectorConfig; 8] {
+        [
+            &self.cpu,
+            &self.memory,
+            &self.disk,
+            &self.network,
+            &self.interrupt,
+            &self.rps,
+            &self.vmstat,
+            &self.gpu,
+        ]
+    }
+
+    /// Intervals the enabled families read at, and `default`, which metrics
+    /// exported by tasks use. Each is exported by a reader of its own.
+    pub fn intervals(&self, default: Duration) -> BTreeSet<Duration> {
+        self.all()
+            .into_iter()
+            .filter(|it| it.enable)
+            .map(|it| it.interval(default))
+            .chain([default])
+            .collect()
+    }
+}
+
+/// `*` matches any run of characters and `?` any single one.
+fn glob(pattern: &str, name: &str) -> bool {
+    let (pattern, name): (Vec<_>, Vec<_>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` is in the pattern and what it matched up to.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|it| *it == '*')
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl Filter {
+    /// Whether to report something known under any of `names`.
+    pub fn accepts(&self, names: &[&str]) -> bool {
+        let any = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| names.iter().any(|name| glob(pattern, name)))
+        };
+        (self.include.is_empty() || any(&self.include)) && !any(&self.exclude)
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.accepts(&[name])
+    }
+}
+
+/// Reads of a gauge family, taken again once they are `interval` old. Every
+/// collection in between reports the same values.
+pub struct Throttle<T, F> {
+    interval: Duration,
+    read: F,
+    last: Mutex<Option<(Instant, T)>>,
+}
+
+impl<T, F> Throttle<T, F>
+where
+    T: Clone,
+    F: Fn() -> Option<T>,
+{
+    pub const fn new(interval: Duration, read: F) -> Self {
+        Self {
+            interval,
+            read,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Option<T> {
+        let mut last = self.last.lock().unwrap();
+        if let Some((at, value)) = &*last {
+            if at.elapsed() < self.interval {
+                return Some(value.clone());
+            }
+        }
+        // Still locked, concurrent collections wait for this read.
+        let value = (self.read)()?;
+        *last = Some((Instant::now(), value.clone()));
+        drop(last);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob("eth*", "eth0"));
+        assert!(glob("*", ""));
+        assert!(glob("nvme?n1", "nvme0n1"));
+        assert!(glob("*-rx-*", "eth0-rx-12"));
+        assert!(!glob("eth*", "veth0"));
+        assert!(!glob("1?", "1"));
+        assert!(!glob("sda", "sda1"));
+    }
+
+    #[test]
+    fn include_then_exclude() {
+        let cfg = CollectorConfig {
+            include: vec!["eth*".to_string(), "ens*".to_string()],
+            exclude: vec!["eth1".to_string()],
+            ..Default::default()
+        };
+        let filter = cfg.filter();
+        assert!(filter.matches("eth0"));
+        assert!(filter.matches("ens3"));
+        assert!(!filter.matches("eth1"));
+        assert!(!filter.matches("lo"));
+        assert!(filter.accepts(&["lo", "eth0"]));
+        assert!(CollectorConfig::default().filter().matches("lo"));
+    }
+
+    #[test]
+    fn export_at_each_interval() {
+        let secs = |it: &[u64]| it.iter().map(|&s| Duration::from_secs(s)).collect();
+        let default = Duration::from_secs(10);
+        let mut cfg = CollectorsConfig::default();
+        assert_eq!(cfg.intervals(default), secs(&[10]));
+        cfg.memory.interval = Some(2);
+        cfg.cpu.interval = Some(60);
+        assert_eq!(cfg.intervals(default), secs(&[2, 10, 60]));
+        cfg.memory.enable = false;
+        assert_eq!(cfg.intervals(default), secs(&[10, 60]));
+    }
+
+    #[test]
+    fn reject_zero_interval() {
+        let cfg: CollectorConfig = toml::from_str("interval = 5").unwrap();
+        assert_eq!(cfg.interval, Some(5));
+        assert!(toml::from_str::<CollectorConfig>("interval = 0").is_err());
+        assert_eq!(
+            toml::from_str::<CollectorConfig>("").unwrap().interval,
+            None
+        );
+    }
+
+    #[test]
+    fn throttle_reads() {
+        let reads = AtomicU32::new(0);
+        let throttle = Throttle::new(Duration::from_secs(3600), || {
+            Some(reads.fetch_add(1, Ordering::Relaxed))
+        });
+        assert_eq!(throttle.get(), Some(0));
+        assert_eq!(throttle.get(), Some(0));
+
+        let throttle = Throttle::new(Duration::ZERO, || {
+            Some(reads.fetch_add(1, Ordering::Relaxed))
+        });
+        assert_eq!(throttle.get(), Some(1));
+        assert_eq!(throttle.get(), Some(2));
+    }
+}
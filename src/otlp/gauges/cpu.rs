@@ -12,27 +12,42 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+use std::sync::Arc;
+
 use opentelemetry::{KeyValue, metrics::ObservableGauge};
-use psh_system::cpu::CpuHandle;
+use psh_system::{System, cpu::CpuHandle};
+
+use crate::otlp::collectors::Throttle;
 
 impl super::super::Otlp {
     pub fn cpu_gauges(&self) -> ObservableGauge<u64> {
         let cpu = CpuHandle::new();
         let host = self.host.clone();
-        let interval = self.interval;
+        let interval = self.collectors.cpu.interval(self.interval);
+        let filter = self.collectors.cpu.filter();
+        let stat = Throttle::new(interval, {
+            let cpu = cpu.clone();
+            move || cpu.stat(Some(interval)).ok()
+        });
+        let utilization = Throttle::new(interval, move || {
+            cpu.utilization(Some(interval)).ok().flatten()
+        });
 
         let gauge = self
-            .meter
+            .meter(interval)
             .u64_observable_gauge("CpuStat")
             .with_description("System profile cpu statistics.")
             .with_callback(move |gauge| {
-                let Ok(cpus) = cpu.stat(Some(interval)) else {
+                let Some(cpus) = stat.get() else {
                     return;
                 };
 
                 let desc =
                     "The amount of time, measured in ticks, the CPU has been in specific states";
                 for (cpu, cpu_time) in cpus.per_cpu.into_iter().enumerate() {
+                    if !filter.matches(&cpu.to_string()) {
+                        continue;
+                    }
                     let gauges = [
                         (
                             cpus.ctxt,
@@ -128,6 +143,119 @@ impl super::super::Otlp {
                 }
             })
             .build();
+
+        self.meter(interval)
+            .f64_observable_gauge("CpuUtilization")
+            .with_unit("1")
+            .with_description("Share of the time each CPU spent in specific states.")
+            .with_callback({
+                let (host, filter) = (self.host.clone(), self.collectors.cpu.filter());
+                move |gauge| {
+                    let Some(cpus) = utilization.get() else {
+                        return;
+                    };
+
+                    for (cpu, u) in cpus.per_cpu.into_iter().enumerate() {
+                        if !filter.matches(&cpu.to_string()) {
+                            continue;
+                        }
+                        let ratios = [
+                            ("user", u.user),
+                            ("nice", u.nice),
+                            ("system", u.system),
+                            ("idle", u.idle),
+                            ("iowait", u.iowait),
+                            ("irq", u.irq),
+                            ("softirq", u.softirq),
+                            ("steal", u.steal),
+                            ("guest", u.guest),
+                            ("guest_nice", u.guest_nice),
+                        ];
+                        for (state, ratio) in ratios {
+                            let a = &[
+                                KeyValue::new("host", host.clone()),
+                                KeyValue::new("cpu", cpu as i64),
+                                KeyValue::new("stat", state),
+                            ];
+                            gauge.observe(ratio, a);
+                        }
+                    }
+                }
+            })
+            .build();
         gauge
     }
+
+    /// Time in each state and events since boot as monotonic sums.
+    pub fn cpu_counters(&self) {
+        let cpu = CpuHandle::new();
+        let interval = self.collectors.cpu.interval(self.interval);
+        let filter = self.collectors.cpu.filter();
+        let stat = Arc::new(Throttle::new(interval, move || {
+            cpu.stat(Some(interval)).ok()
+        }));
+
+        let tick_per_sec = System::default().tick_per_sec as f64;
+        self.meter(interval)
+            .f64_observable_counter("CpuTime")
+            .with_unit("s")
+            .with_description("Time each CPU has spent in specific states.")
+            .with_callback({
+                let (host, stat) = (self.host.clone(), stat.clone());
+                move |counter| {
+                    let Some(cpus) = stat.get() else {
+                        return;
+                    };
+
+                    for (cpu, t) in cpus.per_cpu.into_iter().enumerate() {
+                        if !filter.matches(&cpu.to_string()) {
+                            continue;
+                        }
+                        let times = [
+                            ("user", Some(t.user)),
+                            ("nice", Some(t.nice)),
+                            ("system", Some(t.system)),
+                            ("idle", Some(t.idle)),
+                            ("iowait", t.iowait),
+                            ("irq", t.irq),
+                            ("softirq", t.softirq),
+                            ("steal", t.steal),
+                            ("guest", t.guest),
+                            ("guest_nice", t.guest_nice),
+                        ];
+                        for (state, ticks) in times {
+                            // Not known to older kernels, nothing to count.
+                            let Some(ticks) = ticks else {
+                                continue;
+                            };
+                            let a = &[
+                                KeyValue::new("host", host.clone()),
+                                KeyValue::new("cpu", cpu as i64),
+                                KeyValue::new("stat", state),
+                            ];
+                            counter.observe(ticks as f64 / tick_per_sec, a);
+                        }
+                    }
+                }
+            })
+            .build();
+
+        let host = self.host.clone();
+        self.meter(interval)
+            .u64_observable_counter("CpuEvents")
+            .with_unit("{event}")
+            .with_description("Context switches and forks since boot.")
+            .with_callback(move |counter| {
+                let Some(cpus) = stat.get() else {
+                    return;
+                };
+                for (m, kv) in [
+                    (cpus.ctxt, KeyValue::new("stat", "ctxt")),
+                    (cpus.processes, KeyValue::new("stat", "processes")),
+                ] {
+                    counter.observe(m, &[KeyValue::new("host", host.clone()), kv]);
+                }
+            })
+            .build();
+    }
 }
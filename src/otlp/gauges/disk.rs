@@ -12,24 +12,33 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+use std::sync::Arc;
+
 use opentelemetry::{KeyValue, metrics::ObservableGauge};
 use psh_system::disk::DiskHandle;
 
+use crate::otlp::collectors::Throttle;
+
 impl super::super::Otlp {
     pub fn disk_gagues(&self) -> ObservableGauge<u64> {
         let host = self.host.clone();
-        let interval = self.interval;
+        let interval = self.collectors.disk.interval(self.interval);
+        let filter = self.collectors.disk.filter();
         let disk = DiskHandle::new();
+        let stat = Throttle::new(interval, move || disk.stat(Some(interval)).ok());
 
         let gauge = self
-            .meter
+            .meter(interval)
             .u64_observable_gauge("DiskStat")
             .with_description("System profile disk statistics.")
             .with_callback(move |gauge| {
-                let Ok(disks) = disk.stat(Some(interval)) else {
+                let Some(disks) = stat.get() else {
                     return;
                 };
                 for stat in disks {
+                    if !filter.matches(&stat.name) {
+                        continue;
+                    }
                     let name = stat.name;
 
                     macro_rules! gauges {
@@ -89,4 +98,81 @@ impl super::super::Otlp {
             .build();
         gauge
     }
+
+    /// Cumulative counts of /proc/diskstats as monotonic sums, by unit.
+    pub fn disk_counters(&self) {
+        let interval = self.collectors.disk.interval(self.interval);
+        let filter = self.collectors.disk.filter();
+        let disk = DiskHandle::new();
+        let stat = Arc::new(Throttle::new(interval, move || {
+            let disks = disk.stat(Some(interval)).ok()?;
+            Some(
+                disks
+                    .into_iter()
+                    .filter(|it| filter.matches(&it.name))
+                    .collect::<Vec<_>>(),
+            )
+        }));
+
+        macro_rules! counters {
+            ($name:literal, $unit:literal, $description:literal, [$($stat:ident,)*], [$($stat_o:ident,)*]) => {
+                self.meter(interval)
+                    .u64_observable_counter($name)
+                    .with_unit($unit)
+                    .with_description($description)
+                    .with_callback({
+                        let (host, stat) = (self.host.clone(), stat.clone());
+                        move |counter| {
+                            let Some(disks) = stat.get() else {
+                                return;
+                            };
+                            for stat in disks {
+                                let counts = [
+                                    $((stringify!($stat), Some(stat.$stat)),)*
+                                    $((stringify!($stat_o), stat.$stat_o),)*
+                                ];
+                                for (name, count) in counts {
+                                    let Some(count) = count else {
+                                        continue;
+                                    };
+                                    let a = &[
+                                        KeyValue::new("host", host.clone()),
+                                        KeyValue::new("disk", stat.name.clone()),
+                                        KeyValue::new("stat", name),
+                                    ];
+                                    counter.observe(count, a);
+                                }
+                            }
+                        }
+                    })
+                    .build();
+            };
+        }
+        counters!(
+            "DiskOperations",
+            "{operation}",
+            "Completed and merged disk operations.",
+            [reads, merged, writes, writes_merged,],
+            [discards, discards_merged, flushes,]
+        );
+        counters!(
+            "DiskSectors",
+            "{sector}",
+            "512 bytes sectors transferred by disks.",
+            [sectors_read, sectors_written,],
+            [sectors_discarded,]
+        );
+        counters!(
+            "DiskTime",
+            "ms",
+            "Time disks have spent on operations.",
+            [
+                time_reading,
+                time_writing,
+                time_in_progress,
+                weighted_time_in_progress,
+            ],
+            [time_discarding, time_flushing,]
+        );
+    }
 }
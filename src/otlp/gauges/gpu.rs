@@ -16,26 +16,35 @@ use opentelemetry::{KeyValue, metrics::ObservableGauge};
 use psh_system::gpu::NvidiaHandle;
 use tracing::error;
 
+use crate::otlp::collectors::Throttle;
+
 impl super::super::Otlp {
     pub fn gpu_gauges(&self) -> ObservableGauge<u64> {
         let host = self.host.clone();
-        let interval = self.interval;
+        let interval = self.collectors.gpu.interval(self.interval);
+        let filter = self.collectors.gpu.filter();
         let nvgpu = NvidiaHandle::new();
+        let stat = Throttle::new(interval, move || match nvgpu.stat(Some(interval)) {
+            Ok(stats) => Some(stats),
+            Err(e) => {
+                error!("Failed to collect GPU stats: {}", e);
+                None
+            }
+        });
 
         let gauge = self
-            .meter
+            .meter(interval)
             .u64_observable_gauge("NvGpuStat")
             .with_description("System profile nvgpu statistics.")
             .with_callback(move |gauge| {
-                let gpustats = match nvgpu.stat(Some(interval)) {
-                    Ok(stats) => stats,
-                    Err(e) => {
-                        error!("Failed to collect GPU stats: {}", e);
-                        return;
-                    }
+                let Some(gpustats) = stat.get() else {
+                    return;
                 };
 
                 for stat in gpustats {
+                    if !filter.accepts(&[&stat.uuid, &stat.name]) {
+                        continue;
+                    }
                     // Report per device metrics
                     let device_vals = [
                         // Static fields
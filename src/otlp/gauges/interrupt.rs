@@ -17,29 +17,37 @@ use std::borrow::Cow;
 use opentelemetry::{KeyValue, metrics::ObservableGauge};
 use psh_system::interrupt::InterruptHandle;
 
+use crate::otlp::collectors::Throttle;
+
 impl super::super::Otlp {
     pub fn irq_gauges(&self) -> ObservableGauge<u64> {
         let host = self.host.clone();
-        let interval = self.interval;
+        let interval = self.collectors.interrupt.interval(self.interval);
+        let filter = self.collectors.interrupt.filter();
         let interrupt = InterruptHandle::new();
+        let stat = Throttle::new(interval, move || interrupt.stat(Some(interval)).ok());
 
         let gauge = self
-            .meter
+            .meter(interval)
             .u64_observable_gauge("InterruptStat")
             .with_description("System profile interrupt statistics.")
             .with_callback(move |gauge| {
-                let Ok(irqs) = interrupt.stat(Some(interval)) else {
+                let Some(irqs) = stat.get() else {
                     return;
                 };
 
                 for int in irqs {
+                    let name = int.interrupt_type.to_string();
+                    if !filter.accepts(&[&name, &int.description]) {
+                        continue;
+                    }
                     let desc = Cow::from(int.description);
                     for (cpu, &cnt) in int.cpu_counts.iter().enumerate() {
                         let a = [
                             KeyValue::new("host", host.clone()),
                             KeyValue::new("desc", desc.clone()),
                             KeyValue::new("cpu", cpu as i64),
-                            KeyValue::new("type", int.interrupt_type.to_string()),
+                            KeyValue::new("type", name.clone()),
                         ];
                         gauge.observe(cnt, &a)
                     }
@@ -48,4 +56,40 @@ impl super::super::Otlp {
             .build();
         gauge
     }
+
+    /// Interrupt counts as monotonic sums.
+    pub fn irq_counters(&self) {
+        let host = self.host.clone();
+        let interval = self.collectors.interrupt.interval(self.interval);
+        let filter = self.collectors.interrupt.filter();
+        let interrupts = InterruptHandle::new();
+        let stat = Throttle::new(interval, move || interrupts.stat(Some(interval)).ok());
+
+        self.meter(interval)
+            .u64_observable_counter("Interrupts")
+            .with_unit("{interrupt}")
+            .with_description("System profile interrupt statistics.")
+            .with_callback(move |counter| {
+                let Some(irqs) = stat.get() else {
+                    return;
+                };
+
+                for int in irqs {
+                    let name = int.interrupt_type.to_string();
+                    if !filter.accepts(&[&name, &int.description]) {
+                        continue;
+                    }
+                    for (cpu, &cnt) in int.cpu_counts.iter().enumerate() {
+                        let a = [
+                            KeyValue::new("host", host.clone()),
+                            KeyValue::new("desc", int.description.clone()),
+                            KeyValue::new("cpu", cpu as i64),
+                            KeyValue::new("type", name.clone()),
+                        ];
+                        counter.observe(cnt, &a)
+                    }
+                }
+            })
+            .build();
+    }
 }
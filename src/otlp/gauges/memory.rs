@@ -15,18 +15,22 @@
 use opentelemetry::{KeyValue, metrics::ObservableGauge};
 use psh_system::memory::MemoryHandle;
 
+use crate::otlp::collectors::Throttle;
+
 impl super::super::Otlp {
     pub fn mem_gauges(&self) -> ObservableGauge<u64> {
-        let interval = self.interval;
+        let interval = self.collectors.memory.interval(self.interval);
+        let filter = self.collectors.memory.filter();
         let host = self.host.clone();
         let memory = MemoryHandle::new();
+        let stat = Throttle::new(interval, move || memory.stat(Some(interval)).ok());
 
         let gauge = self
-            .meter
+            .meter(interval)
             .u64_observable_gauge("MemoryStat")
             .with_description("System profile memory statistics.")
             .with_callback(move |gauge| {
-                let Ok(mem) = memory.stat(Some(interval)) else {
+                let Some(mem) = stat.get() else {
                     return;
                 };
 
@@ -58,7 +62,8 @@ impl super::super::Otlp {
                     vmalloc_used,
                     vmalloc_chunk,
                 ];
-                gauges.into_iter().for_each(|(m, kv)| {
+                let wanted = |(_, kv): &(u64, KeyValue)| filter.matches(&kv.value.as_str());
+                gauges.into_iter().filter(wanted).for_each(|(m, kv)| {
                     gauge.observe(m, &[KeyValue::new("host", host.clone()), kv]);
                 });
 
@@ -113,7 +118,7 @@ impl super::super::Otlp {
                     secondary_page_tables,
                 ];
 
-                gauges.into_iter().for_each(|(m, kv)| {
+                gauges.into_iter().filter(wanted).for_each(|(m, kv)| {
                     gauge.observe(m, &[KeyValue::new("host", host.clone()), kv]);
                 })
             })
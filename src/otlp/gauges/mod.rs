@@ -19,4 +19,5 @@ pub mod interrupt;
 pub mod memory;
 pub mod network;
 pub mod rps;
+pub mod semconv;
 pub mod vmstat;
@@ -12,24 +12,33 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+use std::sync::Arc;
+
 use opentelemetry::{KeyValue, metrics::ObservableGauge};
 use psh_system::network::NetworkHandle;
 
+use crate::otlp::collectors::Throttle;
+
 impl super::super::Otlp {
     pub fn net_gauges(&self) -> ObservableGauge<u64> {
-        let interval = self.interval;
+        let interval = self.collectors.network.interval(self.interval);
+        let filter = self.collectors.network.filter();
         let host = self.host.clone();
         let network = NetworkHandle::new();
+        let stat = Throttle::new(interval, move || network.stat(Some(interval)).ok());
 
         let gauge = self
-            .meter
+            .meter(interval)
             .u64_observable_gauge("NetworkStat")
             .with_description("System profile network statistics.")
             .with_callback(move |gauge| {
-                let Ok(stat) = network.stat(Some(interval)) else {
+                let Some(stat) = stat.get() else {
                     return;
                 };
                 for (dev, status) in stat {
+                    if !filter.matches(&dev) {
+                        continue;
+                    }
                     let speed = Self::net_dev_speed(&dev).unwrap_or(0).into();
 
                     macro_rules! gauges {
@@ -79,4 +88,79 @@ impl super::super::Otlp {
             .build();
         gauge
     }
+
+    /// Cumulative counts of /proc/net/dev as monotonic sums, by unit.
+    pub fn net_counters(&self) {
+        let interval = self.collectors.network.interval(self.interval);
+        let filter = self.collectors.network.filter();
+        let network = NetworkHandle::new();
+        let stat = Arc::new(Throttle::new(interval, move || {
+            let mut stat = network.stat(Some(interval)).ok()?;
+            stat.retain(|dev, _| filter.matches(dev));
+            Some(stat)
+        }));
+
+        macro_rules! counters {
+            ($name:literal, $unit:literal, $description:literal, [$($stat:ident,)+]) => {
+                self.meter(interval)
+                    .u64_observable_counter($name)
+                    .with_unit($unit)
+                    .with_description($description)
+                    .with_callback({
+                        let (host, stat) = (self.host.clone(), stat.clone());
+                        move |counter| {
+                            let Some(stat) = stat.get() else {
+                                return;
+                            };
+                            for (dev, status) in stat {
+                                let counts = [$((status.$stat, stringify!($stat)),)+];
+                                for (m, name) in counts {
+                                    let a = [
+                                        KeyValue::new("host", host.clone()),
+                                        KeyValue::new("interface", dev.clone()),
+                                        KeyValue::new("stat", name),
+                                    ];
+                                    counter.observe(m, &a);
+                                }
+                            }
+                        }
+                    })
+                    .build();
+            };
+        }
+        counters!(
+            "NetworkBytes",
+            "By",
+            "Bytes received and sent by network interfaces.",
+            [recv_bytes, sent_bytes,]
+        );
+        counters!(
+            "NetworkPackets",
+            "{packet}",
+            "Packets received and sent by network interfaces.",
+            [
+                recv_packets,
+                recv_compressed,
+                recv_multicast,
+                sent_packets,
+                sent_compressed,
+            ]
+        );
+        counters!(
+            "NetworkErrors",
+            "{error}",
+            "Errors and drops of network interfaces.",
+            [
+                recv_errs,
+                recv_drop,
+                recv_fifo,
+                recv_frame,
+                sent_errs,
+                sent_drop,
+                sent_fifo,
+                sent_colls,
+                sent_carrier,
+            ]
+        );
+    }
 }
@@ -15,21 +15,29 @@
 use opentelemetry::{Array, KeyValue, Value, metrics::ObservableGauge};
 use psh_system::rps::RpsHandle;
 
+use crate::otlp::collectors::Throttle;
+
 impl super::super::Otlp {
     pub fn rps_gauges(&self) -> ObservableGauge<u64> {
         let host = self.host.clone();
+        let interval = self.collectors.rps.interval(self.interval);
+        let filter = self.collectors.rps.filter();
         let rps = RpsHandle::new();
+        let info = Throttle::new(interval, move || rps.info().ok());
 
         let gauge = self
-            .meter
+            .meter(interval)
             .u64_observable_gauge("RpsStat")
             .with_description("System profile rps statistics.")
             .with_callback(move |gauge| {
-                let Ok(rps_details) = rps.info() else {
+                let Some(rps_details) = info.get() else {
                     return;
                 };
                 for detail in rps_details {
                     let dev = detail.dev;
+                    if !filter.matches(&dev) {
+                        continue;
+                    }
                     for rps_queue in detail.queues {
                         let gauges = [(
                             rps_queue.flow_cnt.unwrap_or(0).into(),
@@ -0,0 +1,379 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+//! Host metrics following the OpenTelemetry system semantic conventions, for
+//! the families that have some.
+
+use std::sync::Arc;
+
+use opentelemetry::KeyValue;
+use psh_system::network::NetworkHandle;
+use psh_system::{System, cpu::CpuHandle, disk::DiskHandle, memory::MemoryHandle};
+use serde::Deserialize;
+
+use crate::otlp::collectors::Throttle;
+
+/// Sectors of /proc/diskstats are always 512 bytes.
+const SECTOR_SIZE: u64 = 512;
+
+/// How host metrics are named.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricNaming {
+    /// `CpuStat` and the like, the metric is in a `stat` attribute.
+    #[default]
+    Psh,
+    /// `system.cpu.time` and the like, for cpu, memory, disk and network.
+    Semconv,
+}
+
+impl super::super::Otlp {
+    pub fn cpu_semconv(&self) {
+        let cpu = CpuHandle::new();
+        let interval = self.collectors.cpu.interval(self.interval);
+        let filter = self.collectors.cpu.filter();
+        let stat = Arc::new(Throttle::new(interval, {
+            let cpu = cpu.clone();
+            move || cpu.stat(Some(interval)).ok()
+        }));
+        let utilization = Throttle::new(interval, move || {
+            cpu.utilization(Some(interval)).ok().flatten()
+        });
+
+        self.meter(interval)
+            .f64_observable_gauge("system.cpu.utilization")
+            .with_unit("1")
+            .with_description("Share of the time each logical CPU spent on each mode.")
+            .with_callback({
+                let filter = filter.clone();
+                move |gauge| {
+                    let Some(cpus) = utilization.get() else {
+                        return;
+                    };
+                    for (cpu, u) in cpus.per_cpu.into_iter().enumerate() {
+                        if !filter.matches(&cpu.to_string()) {
+                            continue;
+                        }
+                        let modes = [
+                            ("user", u.user),
+                            ("nice", u.nice),
+                            ("system", u.system),
+                            ("idle", u.idle),
+                            ("iowait", u.iowait),
+                            ("interrupt", u.irq + u.softirq),
+                            ("steal", u.steal),
+                        ];
+                        for (mode, ratio) in modes {
+                            let a = [
+                                KeyValue::new("cpu.logical_number", cpu as i64),
+                                KeyValue::new("cpu.mode", mode),
+                            ];
+                            gauge.observe(ratio, &a);
+                        }
+                    }
+                }
+            })
+            .build();
+
+        let tick_per_sec = System::default().tick_per_sec as f64;
+        self.meter(interval)
+            .f64_observable_counter("system.cpu.time")
+            .with_unit("s")
+            .with_description("Seconds each logical CPU spent on each mode.")
+            .with_callback({
+                let stat = stat.clone();
+                move |counter| {
+                    let Some(cpus) = stat.get() else {
+                        return;
+                    };
+                    for (cpu, t) in cpus.per_cpu.into_iter().enumerate() {
+                        if !filter.matches(&cpu.to_string()) {
+                            continue;
+                        }
+                        // Guest time is already part of user and nice.
+                        let interrupt = t
+                            .irq
+                            .or(t.softirq)
+                            .map(|_| t.irq.unwrap_or(0) + t.softirq.unwrap_or(0));
+                        let modes = [
+                            ("user", Some(t.user)),
+                            ("nice", Some(t.nice)),
+                            ("system", Some(t.system)),
+                            ("idle", Some(t.idle)),
+                            ("iowait", t.iowait),
+                            ("interrupt", interrupt),
+                            ("steal", t.steal),
+                        ];
+                        for (mode, ticks) in modes {
+                            let Some(ticks) = ticks else {
+                                continue;
+                            };
+                            let a = [
+                                KeyValue::new("cpu.logical_number", cpu as i64),
+                                KeyValue::new("cpu.mode", mode),
+                            ];
+                            counter.observe(ticks as f64 / tick_per_sec, &a);
+                        }
+                    }
+                }
+            })
+            .build();
+
+        self.meter(interval)
+            .u64_observable_counter("system.process.created")
+            .with_unit("{process}")
+            .with_description("Total number of processes created over uptime of the host.")
+            .with_callback(move |counter| {
+                if let Some(cpus) = stat.get() {
+                    counter.observe(cpus.processes, &[]);
+                }
+            })
+            .build();
+    }
+
+    pub fn memory_semconv(&self) {
+        let interval = self.collectors.memory.interval(self.interval);
+        let filter = self.collectors.memory.filter();
+        let memory = MemoryHandle::new();
+        let stat = Arc::new(Throttle::new(interval, move || {
+            memory.stat(Some(interval)).ok()
+        }));
+
+        self.meter(interval)
+            .i64_observable_up_down_counter("system.memory.usage")
+            .with_unit("By")
+            .with_description("Reports memory in use by state.")
+            .with_callback({
+                let (stat, filter) = (stat.clone(), filter.clone());
+                move |counter| {
+                    let Some(mem) = stat.get() else {
+                        return;
+                    };
+                    let cached = mem.cached;
+                    let used = mem
+                        .mem_total
+                        .saturating_sub(mem.mem_free + mem.buffers + cached);
+                    let states = [
+                        ("used", used),
+                        ("free", mem.mem_free),
+                        ("buffers", mem.buffers),
+                        ("cached", cached),
+                    ];
+                    for (state, bytes) in states {
+                        if filter.matches(state) {
+                            let a = [KeyValue::new("system.memory.state", state)];
+                            counter.observe(bytes as i64, &a);
+                        }
+                    }
+                }
+            })
+            .build();
+
+        self.meter(interval)
+            .i64_observable_up_down_counter("system.memory.limit")
+            .with_unit("By")
+            .with_description("Total memory available in the system.")
+            .with_callback({
+                let stat = stat.clone();
+                move |counter| {
+                    if let Some(mem) = stat.get() {
+                        counter.observe(mem.mem_total as i64, &[]);
+                    }
+                }
+            })
+            .build();
+
+        self.meter(interval)
+            .i64_observable_up_down_counter("system.paging.usage")
+            .with_unit("By")
+            .with_description("Unix swap or windows pagefile usage.")
+            .with_callback(move |counter| {
+                let Some(mem) = stat.get() else {
+                    return;
+                };
+                let states = [
+                    ("used", mem.swap_total.saturating_sub(mem.swap_free)),
+                    ("free", mem.swap_free),
+                ];
+                for (state, bytes) in states {
+                    if filter.matches(state) {
+                        let a = [KeyValue::new("system.paging.state", state)];
+                        counter.observe(bytes as i64, &a);
+                    }
+                }
+            })
+            .build();
+    }
+
+    pub fn disk_semconv(&self) {
+        let interval = self.collectors.disk.interval(self.interval);
+        let filter = self.collectors.disk.filter();
+        let disk = DiskHandle::new();
+        let stat = Arc::new(Throttle::new(interval, move || {
+            let disks = disk.stat(Some(interval)).ok()?;
+            Some(
+                disks
+                    .into_iter()
+                    .filter(|it| filter.matches(&it.name))
+                    .collect::<Vec<_>>(),
+            )
+        }));
+
+        // Read and write values of every disk.
+        macro_rules! counters {
+            ($ty:ident, $name:literal, $unit:literal, $description:literal, |$d:ident| ($read:expr, $write:expr)) => {
+                self.meter(interval)
+                    .$ty($name)
+                    .with_unit($unit)
+                    .with_description($description)
+                    .with_callback({
+                        let stat = stat.clone();
+                        move |counter| {
+                            let Some(disks) = stat.get() else {
+                                return;
+                            };
+                            for $d in disks {
+                                for (direction, value) in [("read", $read), ("write", $write)] {
+                                    let a = [
+                                        KeyValue::new("system.device", $d.name.clone()),
+                                        KeyValue::new("disk.io.direction", direction),
+                                    ];
+                                    counter.observe(value, &a);
+                                }
+                            }
+                        }
+                    })
+                    .build();
+            };
+        }
+        counters!(
+            u64_observable_counter,
+            "system.disk.io",
+            "By",
+            "Bytes read from and written to disks.",
+            |d| (
+                d.sectors_read * SECTOR_SIZE,
+                d.sectors_written * SECTOR_SIZE
+            )
+        );
+        counters!(
+            u64_observable_counter,
+            "system.disk.operations",
+            "{operation}",
+            "Completed disk operations.",
+            |d| (d.reads, d.writes)
+        );
+        counters!(
+            u64_observable_counter,
+            "system.disk.merged",
+            "{operation}",
+            "Disk operations merged with adjacent ones.",
+            |d| (d.merged, d.writes_merged)
+        );
+        counters!(
+            f64_observable_counter,
+            "system.disk.operation_time",
+            "s",
+            "Sum of the time each operation took to complete.",
+            |d| (
+                d.time_reading as f64 / 1000.0,
+                d.time_writing as f64 / 1000.0
+            )
+        );
+
+        self.meter(interval)
+            .f64_observable_counter("system.disk.io_time")
+            .with_unit("s")
+            .with_description("Time disks spent activated.")
+            .with_callback(move |counter| {
+                let Some(disks) = stat.get() else {
+                    return;
+                };
+                for d in disks {
+                    let a = [KeyValue::new("system.device", d.name)];
+                    counter.observe(d.time_in_progress as f64 / 1000.0, &a);
+                }
+            })
+            .build();
+    }
+
+    pub fn network_semconv(&self) {
+        let interval = self.collectors.network.interval(self.interval);
+        let filter = self.collectors.network.filter();
+        let network = NetworkHandle::new();
+        let stat = Arc::new(Throttle::new(interval, move || {
+            let mut stat = network.stat(Some(interval)).ok()?;
+            stat.retain(|dev, _| filter.matches(dev));
+            Some(stat)
+        }));
+
+        // Receive and transmit counts of every interface.
+        macro_rules! counters {
+            ($name:literal, $unit:literal, $description:literal, $recv:ident, $sent:ident) => {
+                self.meter(interval)
+                    .u64_observable_counter($name)
+                    .with_unit($unit)
+                    .with_description($description)
+                    .with_callback({
+                        let stat = stat.clone();
+                        move |counter| {
+                            let Some(stat) = stat.get() else {
+                                return;
+                            };
+                            for (dev, status) in stat {
+                                let directions =
+                                    [("receive", status.$recv), ("transmit", status.$sent)];
+                                for (direction, value) in directions {
+                                    let a = [
+                                        KeyValue::new("network.interface.name", dev.clone()),
+                                        KeyValue::new("network.io.direction", direction),
+                                    ];
+                                    counter.observe(value, &a);
+                                }
+                            }
+                        }
+                    })
+                    .build();
+            };
+        }
+        counters!(
+            "system.network.io",
+            "By",
+            "Bytes received and transmitted by network interfaces.",
+            recv_bytes,
+            sent_bytes
+        );
+        counters!(
+            "system.network.packets",
+            "{packet}",
+            "Packets received and transmitted by network interfaces.",
+            recv_packets,
+            sent_packets
+        );
+        counters!(
+            "system.network.errors",
+            "{error}",
+            "Errors of network interfaces.",
+            recv_errs,
+            sent_errs
+        );
+        counters!(
+            "system.network.dropped",
+            "{packet}",
+            "Packets dropped by network interfaces.",
+            recv_drop,
+            sent_drop
+        );
+    }
+}
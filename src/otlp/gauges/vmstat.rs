@@ -12,25 +12,47 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+use std::sync::Arc;
+
 use opentelemetry::{KeyValue, metrics::ObservableGauge};
 use psh_system::vmstat::VmstatHandle;
 
+use crate::otlp::collectors::Throttle;
+
+/// `nr_*` entries of /proc/vmstat that count events rather than pages.
+const NR_EVENTS: [&str; 4] = [
+    "nr_dirtied",
+    "nr_written",
+    "nr_foll_pin_acquired",
+    "nr_foll_pin_released",
+];
+
+/// Whether an entry of /proc/vmstat is a current amount, the others only go up.
+fn is_gauge(stat: &str) -> bool {
+    (stat.starts_with("nr_") && !NR_EVENTS.contains(&stat)) || stat == "workingset_nodes"
+}
+
 impl super::super::Otlp {
     pub fn vmstat_gauges(&self) -> ObservableGauge<u64> {
         let host = self.host.clone();
-        let interval = self.interval;
+        let interval = self.collectors.vmstat.interval(self.interval);
+        let filter = self.collectors.vmstat.filter();
         let vmstat = VmstatHandle::new();
+        let stat = Throttle::new(interval, move || vmstat.stat(Some(interval)).ok());
 
         let gauge = self
-            .meter
+            .meter(interval)
             .u64_observable_gauge("VmStat")
             .with_description("System profile vmstat statistics.")
             .with_callback(move |gauge| {
-                let Ok(stat) = vmstat.stat(Some(interval)) else {
+                let Some(stat) = stat.get() else {
                     return;
                 };
 
                 for (k, v) in stat {
+                    if !filter.matches(&k) {
+                        continue;
+                    }
                     gauge.observe(
                         v as u64,
                         &[
@@ -43,4 +65,79 @@ impl super::super::Otlp {
             .build();
         gauge
     }
+
+    /// Entries of /proc/vmstat that only go up as monotonic sums. With
+    /// `gauges`, the others are also registered as gauges, as the semconv
+    /// naming has no legacy vmstat gauge to carry them.
+    pub fn vmstat_counters(&self, gauges: bool) {
+        let interval = self.collectors.vmstat.interval(self.interval);
+        let filter = self.collectors.vmstat.filter();
+        let vmstat = VmstatHandle::new();
+        let stat = Arc::new(Throttle::new(interval, move || {
+            let mut stat = vmstat.stat(Some(interval)).ok()?;
+            stat.retain(|k, _| filter.matches(k));
+            Some(stat)
+        }));
+
+        if gauges {
+            self.meter(interval)
+                .u64_observable_gauge("VmStat")
+                .with_description("System profile vmstat statistics.")
+                .with_callback({
+                    let (host, stat) = (self.host.clone(), stat.clone());
+                    move |gauge| {
+                        let Some(stat) = stat.get() else {
+                            return;
+                        };
+
+                        for (k, v) in stat.into_iter().filter(|(k, _)| is_gauge(k)) {
+                            gauge.observe(
+                                v as u64,
+                                &[
+                                    KeyValue::new("stat", k),
+                                    KeyValue::new("host", host.clone()),
+                                ],
+                            )
+                        }
+                    }
+                })
+                .build();
+        }
+
+        let host = self.host.clone();
+        self.meter(interval)
+            .u64_observable_counter("VmEvents")
+            .with_unit("{event}")
+            .with_description("System profile vmstat event counters.")
+            .with_callback(move |counter| {
+                let Some(stat) = stat.get() else {
+                    return;
+                };
+
+                for (k, v) in stat.into_iter().filter(|(k, _)| !is_gauge(k)) {
+                    counter.observe(
+                        v as u64,
+                        &[
+                            KeyValue::new("stat", k),
+                            KeyValue::new("host", host.clone()),
+                        ],
+                    )
+                }
+            })
+            .build();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_vmstat() {
+        assert!(is_gauge("nr_free_pages"));
+        assert!(is_gauge("workingset_nodes"));
+        assert!(!is_gauge("nr_dirtied"));
+        assert!(!is_gauge("pgfault"));
+        assert!(!is_gauge("workingset_refault_anon"));
+    }
 }
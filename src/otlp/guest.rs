@@ -0,0 +1,299 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use opentelemetry::{
+    KeyValue,
+    metrics::{Histogram, Meter},
+};
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct GuestMetricsConfig {
+    pub enable: bool,
+    /// metrics emitted as monotonic sums instead of gauges, by name
+    pub sums: Vec<String>,
+}
+
+/// Instruments bridged at most. Each new name a task exports registers one
+/// that the meter keeps as long as the daemon runs.
+const MAX_NAMES: usize = 1000;
+
+/// Identifies a series: the task and the sorted tags.
+type SeriesKey = (String, Vec<(String, String)>);
+
+struct Value {
+    value: f64,
+    /// removed once observed, the task will not update it anymore
+    finished: bool,
+}
+
+/// Latest values of one metric, read by its observable instrument.
+#[derive(Default)]
+struct Series {
+    values: Mutex<HashMap<SeriesKey, Value>>,
+}
+
+impl Series {
+    fn set(&self, key: SeriesKey, value: f64) {
+        let mut values = self.values.lock().unwrap();
+        values.insert(
+            key,
+            Value {
+                value,
+                finished: false,
+            },
+        );
+    }
+
+    fn finish(&self, task_id: &str) {
+        let mut values = self.values.lock().unwrap();
+        for ((task, _), value) in values.iter_mut() {
+            if task == task_id {
+                value.finished = true;
+            }
+        }
+    }
+
+    fn observe(&self, host: &str, mut f: impl FnMut(f64, &[KeyValue])) {
+        let mut values = self.values.lock().unwrap();
+        for ((task_id, tags), value) in values.iter() {
+            f(value.value, &attributes(host, task_id, tags));
+        }
+        values.retain(|_, value| !value.finished);
+    }
+}
+
+fn attributes(host: &str, task_id: &str, tags: &[(String, String)]) -> Vec<KeyValue> {
+    let mut attrs = histogram_attributes(host, tags);
+    attrs.push(KeyValue::new("task_id", task_id.to_string()));
+    attrs
+}
+
+fn histogram_attributes(host: &str, tags: &[(String, String)]) -> Vec<KeyValue> {
+    tags.iter()
+        .map(|(k, v)| KeyValue::new(k.clone(), v.clone()))
+        .chain([KeyValue::new("host", host.to_string())])
+        .collect()
+}
+
+struct BridgedHistogram {
+    bounds: Vec<f64>,
+    histogram: Histogram<f64>,
+}
+
+/// Re-emits the samples and points tasks export through the OTLP meter, so
+/// they reach the same backend as the host metrics.
+///
+/// Tags become attributes, a sample is recorded under its name and each
+/// numeric field of a point under `<measurement>.<field>`. Only the latest
+/// value of a series is kept, its timestamp is the collection time.
+/// Observations of the histograms of a task go to OTLP histograms, the
+/// quantiles of its summaries are recorded with a `quantile` tag.
+/// Histograms are cumulative and never forget a series, so they have no
+/// `task_id` attribute and the tasks sharing a series add up. Past
+/// [`MAX_NAMES`] names, values of new names are not bridged.
+pub struct GuestMetrics {
+    host: String,
+    meter: Meter,
+    sums: Vec<String>,
+    series: Mutex<HashMap<String, Arc<Series>>>,
+    histograms: Mutex<HashMap<String, BridgedHistogram>>,
+    /// the name limit was hit and reported
+    full: AtomicBool,
+}
+
+impl GuestMetrics {
+    pub fn new(host: String, meter: Meter, cfg: &GuestMetricsConfig) -> Self {
+        Self {
+            host,
+            meter,
+            sums: cfg.sums.clone(),
+            series: Mutex::new(HashMap::new()),
+            histograms: Mutex::new(HashMap::new()),
+            full: AtomicBool::new(false),
+        }
+    }
+
+    pub fn record(&self, task_id: &str, name: &str, tags: &[(String, String)], value: f64) {
+        let mut tags = tags.to_vec();
+        tags.sort_unstable();
+        if let Some(series) = self.series(name) {
+            series.set((task_id.to_string(), tags), value);
+        }
+    }
+
+    /// Histogram instrument for the distribution `name`, all tasks must
+    /// register it with the same bounds. None past [`MAX_NAMES`] names.
+    pub fn histogram(&self, name: &str, bounds: &[f64]) -> Result<Option<Histogram<f64>>, String> {
+        let mut histograms = self.histograms.lock().unwrap();
+        if let Some(registered) = histograms.get(name) {
+            return if registered.bounds == bounds {
+                Ok(Some(registered.histogram.clone()))
+            } else {
+                Err(format!(
+                    "Histogram {} has other bounds in another task",
+                    name
+                ))
+            };
+        }
+        if histograms.len() >= MAX_NAMES {
+            drop(histograms);
+            self.report_full();
+            return Ok(None);
+        }
+
+        let histogram = self
+            .meter
+            .f64_histogram(name.to_string())
+            .with_description("Aggregated from the observations of psh tasks.")
+            .with_boundaries(bounds.to_vec())
+            .build();
+        histograms.insert(
+            name.to_string(),
+            BridgedHistogram {
+                bounds: bounds.to_vec(),
+                histogram: histogram.clone(),
+            },
+        );
+        drop(histograms);
+        Ok(Some(histogram))
+    }
+
+    /// Attributes of the histogram series `tags`.
+    pub fn histogram_attributes(&self, tags: &[(String, String)]) -> Vec<KeyValue> {
+        histogram_attributes(&self.host, tags)
+    }
+
+    /// The task is over, its series go away after the next collection.
+    pub fn finish(&self, task_id: &str) {
+        let series: Vec<_> = self.series.lock().unwrap().values().cloned().collect();
+        for series in series {
+            series.finish(task_id);
+        }
+    }
+
+    fn series(&self, name: &str) -> Option<Arc<Series>> {
+        let mut all = self.series.lock().unwrap();
+        if let Some(series) = all.get(name) {
+            return Some(series.clone());
+        }
+        if all.len() >= MAX_NAMES {
+            drop(all);
+            self.report_full();
+            return None;
+        }
+
+        let series = Arc::new(Series::default());
+        let observed = series.clone();
+        let host = self.host.clone();
+        let description = "Exported by a psh task.";
+        if self.sums.iter().any(|it| it == name) {
+            self.meter
+                .f64_observable_counter(name.to_string())
+                .with_description(description)
+                .with_callback(move |counter| {
+                    observed.observe(&host, |v, attrs| counter.observe(v, attrs))
+                })
+                .build();
+        } else {
+            self.meter
+                .f64_observable_gauge(name.to_string())
+                .with_description(description)
+                .with_callback(move |gauge| {
+                    observed.observe(&host, |v, attrs| gauge.observe(v, attrs))
+                })
+                .build();
+        }
+        all.insert(name.to_string(), series.clone());
+        drop(all);
+        Some(series)
+    }
+
+    fn report_full(&self) {
+        if !self.full.swap(true, Ordering::Relaxed) {
+            tracing::warn!(
+                "Guest metrics have {} names, values of new ones are not bridged",
+                MAX_NAMES
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn observed(series: &Series) -> Vec<(f64, Vec<KeyValue>)> {
+        let mut observed = Vec::new();
+        series.observe("h", |v, attrs| observed.push((v, attrs.to_vec())));
+        observed
+    }
+
+    #[test]
+    fn keep_latest_until_finished() {
+        let series = Series::default();
+        let tags = vec![("cpu".to_string(), "0".to_string())];
+        series.set(("1".to_string(), tags.clone()), 1.0);
+        series.set(("1".to_string(), tags.clone()), 2.0);
+        series.set(("2".to_string(), tags), 3.0);
+
+        let mut values: Vec<_> = observed(&series).into_iter().map(|it| it.0).collect();
+        values.sort_by(f64::total_cmp);
+        assert_eq!(values, [2.0, 3.0]);
+
+        // Still reported once after the task is over.
+        series.finish("1");
+        assert_eq!(observed(&series).len(), 2);
+        let observed = observed(&series);
+        assert_eq!(observed.len(), 1);
+        assert_eq!(
+            observed[0].1,
+            [
+                KeyValue::new("cpu", "0"),
+                KeyValue::new("host", "h"),
+                KeyValue::new("task_id", "2")
+            ]
+        );
+    }
+
+    #[test]
+    fn cap_names() {
+        let meter = opentelemetry::global::meter("test");
+        let metrics = GuestMetrics::new("h".to_string(), meter, &GuestMetricsConfig::default());
+        for i in 0..=MAX_NAMES {
+            metrics.record("1", &format!("m{}", i), &[], 1.0);
+        }
+        assert_eq!(metrics.series.lock().unwrap().len(), MAX_NAMES);
+        assert!(metrics.series("m0").is_some());
+        assert!(metrics.series(&format!("m{}", MAX_NAMES)).is_none());
+    }
+
+    #[test]
+    fn reject_other_bounds() {
+        let meter = opentelemetry::global::meter("test");
+        let metrics = GuestMetrics::new("h".to_string(), meter, &GuestMetricsConfig::default());
+        assert!(metrics.histogram("latency", &[1.0, 2.0]).unwrap().is_some());
+        assert!(metrics.histogram("latency", &[1.0, 2.0]).unwrap().is_some());
+        assert!(metrics.histogram("latency", &[1.0, 5.0]).is_err());
+    }
+}
@@ -12,9 +12,17 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+pub mod collectors;
 pub mod gauges;
+mod guest;
+pub mod prometheus;
 
-use std::{sync::LazyLock, time::Duration};
+pub use collectors::CollectorsConfig;
+pub use gauges::semconv::MetricNaming;
+pub use guest::{GuestMetrics, GuestMetricsConfig};
+pub use prometheus::{PrometheusConfig, ScrapeReader};
+
+use std::{collections::BTreeMap, sync::LazyLock, time::Duration};
 
 use anyhow::Result;
 use opentelemetry::{
@@ -37,27 +45,66 @@ static NET_DEV_SPEED: LazyLock<TinyUfo<String, Option<u32>>> =
 pub struct Otlp {
     host: String,
     interval: Duration,
-    meter: Meter,
+    collectors: CollectorsConfig,
+    naming: MetricNaming,
+    /// A meter per interval families read at, each on a provider of its own
+    /// that exports at that interval.
+    meters: BTreeMap<Duration, Meter>,
     // NOTE: the field avoid provider early drop see: <https://github.com/open-telemetry/opentelemetry-rust/issues/1661>
-    _provider: SdkMeterProvider,
+    _providers: Vec<SdkMeterProvider>,
 }
 
 impl Otlp {
-    pub fn new(token: String, interval: Duration, export_config: ExportConfig) -> Result<Self> {
-        let provider = Self::meter_provider(export_config, &token, interval)?;
+    /// Metrics are pushed to the collector of `export_config` and served to
+    /// the scrapes of `scrape`, either of them may be left out.
+    pub fn new(
+        token: String,
+        interval: Duration,
+        collectors: CollectorsConfig,
+        naming: MetricNaming,
+        export_config: Option<ExportConfig>,
+        scrape: Option<ScrapeReader>,
+    ) -> Result<Self> {
         let host = nix::unistd::gethostname()
             .ok()
             .map(|v| v.to_string_lossy().to_string())
             .unwrap_or_else(|| token.clone());
-        let meter = provider.meter("SystemProfile");
+        let mut meters = BTreeMap::new();
+        let mut providers = Vec::new();
+        for export_interval in collectors.intervals(interval) {
+            let provider = Self::meter_provider(
+                export_config.as_ref(),
+                scrape.as_ref(),
+                &token,
+                &host,
+                export_interval,
+            )?;
+            meters.insert(export_interval, provider.meter("SystemProfile"));
+            providers.push(provider);
+        }
         Ok(Self {
             host,
             interval,
-            meter,
-            _provider: provider,
+            collectors,
+            naming,
+            meters,
+            _providers: providers,
         })
     }
 
+    /// Meter of the families that read every `interval`.
+    fn meter(&self, interval: Duration) -> &Meter {
+        self.meters
+            .get(&interval)
+            .unwrap_or_else(|| &self.meters[&self.interval])
+    }
+
+    /// Bridge for metrics exported by tasks, reported along with the host metrics.
+    pub fn guest_metrics(&self, cfg: &GuestMetricsConfig) -> GuestMetrics {
+        let meter = self.meter(self.interval).clone();
+        GuestMetrics::new(self.host.clone(), meter, cfg)
+    }
+
     pub fn net_dev_speed(name: &String) -> Option<u32> {
         if let Some(speed) = NET_DEV_SPEED.get(name) {
             return speed;
@@ -72,14 +119,57 @@ impl Otlp {
     pub async fn otlp_tasks(&self) {
         let interval = self.interval;
 
-        self.mem_gauges();
-        self.net_gauges();
-        self.disk_gagues();
-        self.irq_gauges();
-        self.cpu_gauges();
-        self.rps_gauges();
-        self.vmstat_gauges();
-        self.gpu_gauges();
+        let collectors = &self.collectors;
+        let semconv = self.naming == MetricNaming::Semconv;
+        if collectors.memory.enable {
+            if semconv {
+                self.memory_semconv();
+            } else {
+                self.mem_gauges();
+            }
+        }
+        if collectors.network.enable {
+            if semconv {
+                self.network_semconv();
+            } else {
+                self.net_gauges();
+                self.net_counters();
+            }
+        }
+        if collectors.disk.enable {
+            if semconv {
+                self.disk_semconv();
+            } else {
+                self.disk_gagues();
+                self.disk_counters();
+            }
+        }
+        if collectors.interrupt.enable {
+            if !semconv {
+                self.irq_gauges();
+            }
+            self.irq_counters();
+        }
+        if collectors.cpu.enable {
+            if semconv {
+                self.cpu_semconv();
+            } else {
+                self.cpu_gauges();
+                self.cpu_counters();
+            }
+        }
+        if collectors.rps.enable {
+            self.rps_gauges();
+        }
+        if collectors.vmstat.enable {
+            if !semconv {
+                self.vmstat_gauges();
+            }
+            self.vmstat_counters(semconv);
+        }
+        if collectors.gpu.enable {
+            self.gpu_gauges();
+        }
 
         loop {
             tokio::time::sleep(interval).await;
@@ -87,30 +177,40 @@ impl Otlp {
     }
 
     fn meter_provider(
-        export_config: ExportConfig,
+        export_config: Option<&ExportConfig>,
+        scrape: Option<&ScrapeReader>,
         token: &str,
+        host: &str,
         interval: Duration,
     ) -> Result<SdkMeterProvider> {
-        let mut meta = MetadataMap::new();
-        meta.insert("authorization", format!("Bearer {}", token).parse()?);
-        let otlp_exporter = MetricExporter::builder()
-            .with_tonic()
-            .with_tls_config(ClientTlsConfig::new().with_native_roots())
-            .with_metadata(meta)
-            .with_timeout(Duration::from_secs(10))
-            .with_export_config(export_config)
-            .build()?;
-        let reader = PeriodicReader::builder(otlp_exporter)
-            .with_interval(interval)
-            .build();
         let resource = Resource::builder()
             .with_attribute(KeyValue::new("service.name", "PSH"))
+            .with_attribute(KeyValue::new("host.name", host.to_owned()))
             .build();
-        let a = SdkMeterProvider::builder()
-            .with_reader(reader)
-            .with_resource(resource)
-            .build();
+        let mut builder = SdkMeterProvider::builder().with_resource(resource);
+        if let Some(export_config) = export_config {
+            let mut meta = MetadataMap::new();
+            meta.insert("authorization", format!("Bearer {}", token).parse()?);
+            let otlp_exporter = MetricExporter::builder()
+                .with_tonic()
+                .with_tls_config(ClientTlsConfig::new().with_native_roots())
+                .with_metadata(meta)
+                .with_timeout(Duration::from_secs(10))
+                .with_export_config(ExportConfig {
+                    endpoint: export_config.endpoint.clone(),
+                    protocol: export_config.protocol,
+                    timeout: export_config.timeout,
+                })
+                .build()?;
+            let reader = PeriodicReader::builder(otlp_exporter)
+                .with_interval(interval)
+                .build();
+            builder = builder.with_reader(reader);
+        }
+        if let Some(scrape) = scrape {
+            builder = builder.with_reader(scrape.reader());
+        }
 
-        Ok(a)
+        Ok(builder.build())
     }
 }
@@ -0,0 +1,425 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    fmt::Write as _,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::{
+    error::{OTelSdkError, OTelSdkResult},
+    metrics::{
+        InstrumentKind, ManualReader, Pipeline, Temporality,
+        data::{AggregatedMetrics, MetricData, ResourceMetrics},
+        reader::MetricReader,
+    },
+};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// Scrapers send a request line and a few headers, nothing more.
+const MAX_REQUEST_LEN: usize = 8192;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PrometheusConfig {
+    pub enable: bool,
+    /// address of the `/metrics` endpoint
+    pub listen: String,
+}
+
+/// Readers the meter providers share with the endpoint, which collects
+/// through each of them on every scrape.
+#[derive(Debug, Clone, Default)]
+pub struct ScrapeReader(Arc<Mutex<Vec<Arc<ManualReader>>>>);
+
+impl ScrapeReader {
+    /// Reader for one more meter provider.
+    pub fn reader(&self) -> impl MetricReader {
+        let reader = Arc::new(ManualReader::default());
+        self.0.lock().unwrap().push(reader.clone());
+        SharedReader(reader)
+    }
+
+    /// What every provider holds, in the order their readers were made.
+    fn collect(&self) -> Result<Vec<ResourceMetrics>, OTelSdkError> {
+        let readers = self.0.lock().unwrap().clone();
+        readers
+            .iter()
+            .map(|reader| {
+                let mut rm = ResourceMetrics::default();
+                reader.collect(&mut rm).map(|_| rm)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct SharedReader(Arc<ManualReader>);
+
+impl MetricReader for SharedReader {
+    fn register_pipeline(&self, pipeline: std::sync::Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> OTelSdkResult {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+/// Metric or label name with what Prometheus does not allow replaced by `_`.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+trait Number: Copy {
+    fn write(self, out: &mut String);
+}
+
+impl Number for u64 {
+    fn write(self, out: &mut String) {
+        let _ = write!(out, "{}", self);
+    }
+}
+
+impl Number for i64 {
+    fn write(self, out: &mut String) {
+        let _ = write!(out, "{}", self);
+    }
+}
+
+impl Number for f64 {
+    fn write(self, out: &mut String) {
+        let _ = match self {
+            Self::INFINITY => write!(out, "+Inf"),
+            Self::NEG_INFINITY => write!(out, "-Inf"),
+            _ if self.is_nan() => write!(out, "NaN"),
+            _ => write!(out, "{}", self),
+        };
+    }
+}
+
+fn family(out: &mut String, name: &str, ty: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {} {}", name, ty);
+    if !help.is_empty() {
+        let help = help.replace('\\', r"\\").replace('\n', r"\n");
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+    }
+}
+
+fn sample<'a>(
+    out: &mut String,
+    name: &str,
+    attrs: impl Iterator<Item = &'a KeyValue>,
+    le: Option<f64>,
+    value: impl Number,
+) {
+    out.push_str(name);
+    let mut labels: Vec<_> = attrs
+        .map(|kv| (sanitize(kv.key.as_str()), kv.value.as_str().into_owned()))
+        .collect();
+    if let Some(le) = le {
+        let mut bound = String::new();
+        le.write(&mut bound);
+        labels.push(("le".to_string(), bound));
+    }
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (key, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let value = value
+                .replace('\\', r"\\")
+                .replace('"', r#"\""#)
+                .replace('\n', r"\n");
+            let _ = write!(out, "{}=\"{}\"", key, value);
+        }
+        out.push('}');
+    }
+    out.push(' ');
+    value.write(out);
+    out.push('\n');
+}
+
+fn encode_data<T: Number>(out: &mut String, name: &str, help: &str, data: &MetricData<T>) {
+    match data {
+        MetricData::Gauge(gauge) => {
+            family(out, name, "gauge", help);
+            for point in gauge.data_points() {
+                sample(out, name, point.attributes(), None, point.value());
+            }
+        }
+        MetricData::Sum(sum) if sum.is_monotonic() => {
+            let name = name.strip_suffix("_total").unwrap_or(name);
+            family(out, name, "counter", help);
+            let total = format!("{}_total", name);
+            for point in sum.data_points() {
+                sample(out, &total, point.attributes(), None, point.value());
+            }
+        }
+        MetricData::Sum(sum) => {
+            family(out, name, "gauge", help);
+            for point in sum.data_points() {
+                sample(out, name, point.attributes(), None, point.value());
+            }
+        }
+        MetricData::Histogram(histogram) => {
+            family(out, name, "histogram", help);
+            let (bucket, count, sum) = (
+                format!("{}_bucket", name),
+                format!("{}_count", name),
+                format!("{}_sum", name),
+            );
+            for point in histogram.data_points() {
+                let bounds = point.bounds().chain([f64::INFINITY]);
+                let mut cumulative = 0;
+                for (bound, n) in bounds.zip(point.bucket_counts()) {
+                    cumulative += n;
+                    sample(out, &bucket, point.attributes(), Some(bound), cumulative);
+                }
+                sample(out, &count, point.attributes(), None, point.count());
+                sample(out, &sum, point.attributes(), None, point.sum());
+            }
+        }
+        // Not produced by psh.
+        MetricData::ExponentialHistogram(_) => {}
+    }
+}
+
+/// OpenMetrics text of everything collected.
+pub fn encode(rms: &[ResourceMetrics]) -> String {
+    let mut out = String::new();
+    let scopes = rms.iter().flat_map(|it| it.scope_metrics());
+    for metric in scopes.flat_map(|it| it.metrics()) {
+        let name = sanitize(metric.name());
+        let help = metric.description();
+        match metric.data() {
+            AggregatedMetrics::F64(data) => encode_data(&mut out, &name, help, data),
+            AggregatedMetrics::U64(data) => encode_data(&mut out, &name, help, data),
+            AggregatedMetrics::I64(data) => encode_data(&mut out, &name, help, data),
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+async fn handle(mut stream: TcpStream, reader: ScrapeReader) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    // Only the request line matters, headers are read and ignored.
+    while !request.windows(4).any(|it| it == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            bail!("Request is too large");
+        }
+        let n = tokio::time::timeout(READ_TIMEOUT, stream.read(&mut buf)).await??;
+        if n == 0 {
+            bail!("Connection closed before the end of the request");
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.split_whitespace();
+    let method = request_line.next();
+    let path = request_line
+        .next()
+        .and_then(|it| it.split('?').next())
+        .unwrap_or_default();
+
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), "/metrics") => {
+            // The gauge callbacks read procfs and sysfs.
+            let collected =
+                tokio::task::spawn_blocking(move || reader.collect().map(|rms| encode(&rms)))
+                    .await?;
+            match collected {
+                Ok(body) => ("200 OK", CONTENT_TYPE, body),
+                Err(e) => (
+                    "500 Internal Server Error",
+                    "text/plain",
+                    format!("{}\n", e),
+                ),
+            }
+        }
+        (Some("GET"), _) => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_string(),
+        ),
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn serve_on(listener: TcpListener, reader: ScrapeReader) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let reader = reader.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, reader).await {
+                tracing::debug!("Failed to serve metrics to {}: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+/// Serve what `reader` collects at `/metrics` of the configured address.
+pub async fn serve(cfg: &PrometheusConfig, reader: ScrapeReader) -> Result<()> {
+    let listener = TcpListener::bind(&cfg.listen)
+        .await
+        .with_context(|| format!("Failed to listen on {}", cfg.listen))?;
+    serve_on(listener, reader).await
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+
+    use super::*;
+    use crate::TOKIO_RUNTIME;
+
+    fn provider(reader: &ScrapeReader) -> SdkMeterProvider {
+        let provider = SdkMeterProvider::builder()
+            .with_reader(reader.reader())
+            .build();
+        let meter = provider.meter("test");
+        meter
+            .u64_observable_gauge("CpuStat")
+            .with_description("cpu")
+            .with_callback(|gauge| gauge.observe(3, &[KeyValue::new("desc", "a \"b\"")]))
+            .build();
+        meter
+            .f64_observable_counter("io.bytes")
+            .with_callback(|counter| counter.observe(1.5, &[]))
+            .build();
+        let histogram = meter
+            .f64_histogram("lat")
+            .with_boundaries(vec![1.0, 5.0])
+            .build();
+        for value in [0.5, 3.0, 10.0] {
+            histogram.record(value, &[]);
+        }
+        provider
+    }
+
+    #[test]
+    fn encode_openmetrics() {
+        let reader = ScrapeReader::default();
+        let _provider = provider(&reader);
+        let text = encode(&reader.collect().unwrap());
+
+        for line in [
+            "# TYPE CpuStat gauge",
+            "# HELP CpuStat cpu",
+            r#"CpuStat{desc="a \"b\""} 3"#,
+            "# TYPE io_bytes counter",
+            "io_bytes_total 1.5",
+            "# TYPE lat histogram",
+            r#"lat_bucket{le="1"} 1"#,
+            r#"lat_bucket{le="5"} 2"#,
+            r#"lat_bucket{le="+Inf"} 3"#,
+            "lat_count 3",
+            "lat_sum 13.5",
+        ] {
+            assert!(
+                text.lines().any(|it| it == line),
+                "{} not in {}",
+                line,
+                text
+            );
+        }
+        assert!(text.ends_with("# EOF\n"));
+    }
+
+    #[test]
+    fn scrape_every_provider() {
+        let reader = ScrapeReader::default();
+        let _provider = provider(&reader);
+        let other = SdkMeterProvider::builder()
+            .with_reader(reader.reader())
+            .build();
+        other
+            .meter("other")
+            .u64_observable_gauge("MemStat")
+            .with_callback(|gauge| gauge.observe(7, &[]))
+            .build();
+
+        let text = encode(&reader.collect().unwrap());
+        assert!(text.lines().any(|it| it.starts_with("CpuStat{")));
+        assert!(text.lines().any(|it| it == "MemStat 7"));
+        assert_eq!(text.matches("# EOF").count(), 1);
+    }
+
+    #[test]
+    fn serve_metrics() {
+        let reader = ScrapeReader::default();
+        let _provider = provider(&reader);
+        let response = TOKIO_RUNTIME.block_on(async move {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(serve_on(listener, reader));
+
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream
+                .write_all(b"GET /metrics HTTP/1.1\r\nHost: psh\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        });
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(CONTENT_TYPE));
+        assert!(response.ends_with("# EOF\n"));
+    }
+}
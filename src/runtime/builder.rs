@@ -12,24 +12,35 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+use std::sync::{Arc, atomic::Ordering};
+
 use anyhow::Context;
 use host_op_perf::PerfCtx;
-use host_op_system::SysCtx;
+use host_op_system::{SysCtx, SystemInterfaces};
 use wasmtime::{
     Config, Engine, Store,
     component::{Linker, ResourceTable},
 };
 use wasmtime_wasi::{DirPerms, FilePerms, StdinStream, StdoutStream, WasiCtxBuilder};
 
-use super::{DataExportCtx, PshEngine, PshState, data_export};
+use super::{
+    DataExportCtx, PshEngine, PshState,
+    cache::ComponentCache,
+    data_export, distribution,
+    limits::{Limiter, TaskLimits},
+};
 
 #[allow(dead_code)]
 pub struct PshEngineBuilder {
     wasi_ctx_builder: WasiCtxBuilder,
     engine_config: Config,
     use_perf_op: bool,
-    use_system_op: bool,
+    system_interfaces: SystemInterfaces,
+    /// paths preopened read-only for the guest
+    preopened_dirs: Vec<String>,
     data_export_ctx: Option<DataExportCtx>,
+    limits: TaskLimits,
+    component_cache: Option<Arc<ComponentCache>>,
 }
 
 #[allow(dead_code)]
@@ -42,47 +53,79 @@ impl PshEngineBuilder {
             wasi_ctx_builder: WasiCtxBuilder::new(),
             engine_config,
             use_perf_op: false,
-            use_system_op: false,
+            system_interfaces: SystemInterfaces::none(),
+            preopened_dirs: vec!["/".to_string()],
             data_export_ctx: None,
+            limits: TaskLimits::default(),
+            component_cache: None,
         }
     }
 
     pub fn build(mut self) -> anyhow::Result<PshEngine> {
+        let fuel = self.limits.fuel;
+        if fuel.is_some() {
+            self.engine_config.consume_fuel(true);
+        }
         let engine = Engine::new(&self.engine_config).context("Failed to create Wasi Engine.")?;
         let mut linker: Linker<PshState> = Linker::new(&engine);
         wasmtime_wasi::add_to_linker_sync(&mut linker)
             .context("Failed to link wasi sync module")?;
         if self.use_perf_op {
-            host_op_perf::add_to_linker(&mut linker, |state| &mut state.perf_ctx)
-                .context("Failed to link perf module")?;
+            host_op_perf::add_to_linker(&mut linker, |state| {
+                state.host_calls.perf.fetch_add(1, Ordering::Relaxed);
+                &mut state.perf_ctx
+            })
+            .context("Failed to link perf module")?;
         }
-        if self.use_system_op {
-            host_op_system::add_to_linker(&mut linker, |state| &mut state.sys_ctx)
-                .context("Failed to link system module")?;
+        if !self.system_interfaces.is_empty() {
+            host_op_system::add_to_linker_with(
+                &mut linker,
+                |state| {
+                    state.host_calls.system.fetch_add(1, Ordering::Relaxed);
+                    &mut state.sys_ctx
+                },
+                &self.system_interfaces,
+            )
+            .context("Failed to link system module")?;
         }
         if self.data_export_ctx.is_some() {
-            data_export::add_to_linker(&mut linker, |state| &mut state.data_export_ctx)
-                .context("Failed to link data-export module")?;
+            data_export::add_to_linker(&mut linker, |state| {
+                state.host_calls.data_export.fetch_add(1, Ordering::Relaxed);
+                &mut state.data_export_ctx
+            })
+            .context("Failed to link data-export module")?;
+            distribution::add_to_linker(&mut linker, |state| {
+                state.host_calls.data_export.fetch_add(1, Ordering::Relaxed);
+                &mut state.data_export_ctx
+            })
+            .context("Failed to link distribution module")?;
         }
 
-        self.wasi_ctx_builder
-            .preopened_dir("/", "/", DirPerms::READ, FilePerms::READ)?;
+        for dir in &self.preopened_dirs {
+            self.wasi_ctx_builder
+                .preopened_dir(dir, dir, DirPerms::READ, FilePerms::READ)
+                .with_context(|| format!("Failed to preopen {}", dir))?;
+        }
 
         let state = PshState {
             name: "PSH Wasi Runtime".to_owned(),
             table: ResourceTable::new(),
             wasi_ctx: self.wasi_ctx_builder.build(),
             perf_ctx: PerfCtx::new(),
-            sys_ctx: SysCtx::default(),
-            data_export_ctx: self.data_export_ctx.unwrap_or(DataExportCtx { ctx: None }),
+            sys_ctx: SysCtx::new(self.system_interfaces),
+            data_export_ctx: self
+                .data_export_ctx
+                .unwrap_or_else(|| DataExportCtx::new(None, None, Default::default())),
+            limiter: Limiter::new(self.limits),
+            host_calls: Default::default(),
         };
-        let store = Store::new(&engine, state);
+        let mut store = Store::new(&engine, state);
+        store.limiter(|state| &mut state.limiter);
+        if let Some(fuel) = fuel {
+            store.set_fuel(fuel)?;
+        }
 
-        Ok(PshEngine {
-            engine,
-            store,
-            linker,
-        })
+        Ok(PshEngine::new(engine, store, linker, self.component_cache))
     }
 
     pub fn wasi_stdin(mut self, stdin: impl StdinStream + 'static) -> Self {
@@ -165,7 +208,22 @@ impl PshEngineBuilder {
     }
 
     pub const fn allow_system_op(mut self, enable: bool) -> Self {
-        self.use_system_op = enable;
+        self.system_interfaces = if enable {
+            SystemInterfaces::all()
+        } else {
+            SystemInterfaces::none()
+        };
+        self
+    }
+
+    pub const fn allow_system_interfaces(mut self, interfaces: SystemInterfaces) -> Self {
+        self.system_interfaces = interfaces;
+        self
+    }
+
+    /// Replace the read-only preopened dirs, `/` by default.
+    pub fn wasi_readonly_dirs(mut self, dirs: &[impl AsRef<str>]) -> Self {
+        self.preopened_dirs = dirs.iter().map(|d| d.as_ref().to_string()).collect();
         self
     }
 
@@ -173,4 +231,14 @@ impl PshEngineBuilder {
         self.data_export_ctx = ctx;
         self
     }
+
+    pub const fn limits(mut self, limits: TaskLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn component_cache(mut self, cache: Option<Arc<ComponentCache>>) -> Self {
+        self.component_cache = cache;
+        self
+    }
 }
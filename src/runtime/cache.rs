@@ -0,0 +1,223 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    fs::{self, File},
+    hash::{DefaultHasher, Hash, Hasher},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use wasmtime::{Engine, component::Component};
+
+const ENTRY_EXTENSION: &str = "cwasm";
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ComponentCacheConfig {
+    pub path: String,
+    /// in bytes, least recently used entries are evicted above it
+    pub max_size: u64,
+}
+
+/// Content addressed cache of precompiled components.
+///
+/// Entries live in `<path>/<engine hash>/<wasm sha256>.cwasm`, the engine hash
+/// covers the wasmtime version and every setting affecting compilation, so
+/// upgrading wasmtime or changing the engine config never loads a stale entry.
+pub struct ComponentCache {
+    dir: PathBuf,
+    max_size: u64,
+    /// only one worker evicts at a time
+    evicting: Mutex<()>,
+    tmp_id: AtomicUsize,
+}
+
+impl ComponentCache {
+    pub fn new(cfg: &ComponentCacheConfig) -> Result<Self> {
+        let dir = PathBuf::from(&cfg.path);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create component cache {}", dir.display()))?;
+        Ok(Self {
+            dir,
+            max_size: cfg.max_size,
+            evicting: Mutex::new(()),
+            tmp_id: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn load_or_compile(&self, engine: &Engine, wasm: &[u8]) -> Result<Component> {
+        let path = self.entry_path(engine, wasm);
+        if path.exists() {
+            // SAFETY: entries are only written by `insert` from `Component::serialize`
+            // and the cache dir is not supposed to be writable by anyone but psh.
+            match unsafe { Component::deserialize_file(engine, &path) } {
+                Ok(component) => {
+                    // mtime is the recency used by eviction
+                    let _ = File::options()
+                        .write(true)
+                        .open(&path)
+                        .and_then(|f| f.set_modified(SystemTime::now()));
+                    return Ok(component);
+                }
+                Err(e) => tracing::warn!("Discard cached component {}: {:#}", path.display(), e),
+            }
+        }
+
+        let component = Component::from_binary(engine, wasm)?;
+        if let Err(e) = self.insert(&path, &component) {
+            tracing::warn!("Failed to cache component {}: {:#}", path.display(), e);
+        }
+        Ok(component)
+    }
+
+    fn entry_path(&self, engine: &Engine, wasm: &[u8]) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+        let engine_key = format!("{:016x}", hasher.finish());
+        let wasm_key = hex::encode(Sha256::digest(wasm));
+        self.dir
+            .join(engine_key)
+            .join(wasm_key)
+            .with_extension(ENTRY_EXTENSION)
+    }
+
+    fn insert(&self, path: &Path, component: &Component) -> Result<()> {
+        let bytes = component.serialize()?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Rename a complete file into place so readers never see a partial entry,
+        // other workers may be compiling the same component.
+        let tmp_id = self.tmp_id.fetch_add(1, Ordering::Relaxed);
+        let tmp = path.with_extension(format!("{}-{}.tmp", process::id(), tmp_id));
+        fs::write(&tmp, bytes)?;
+        if let Err(e) = fs::rename(&tmp, path) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        self.evict()
+    }
+
+    /// Remove least recently used entries until the cache fits in `max_size`.
+    fn evict(&self) -> Result<()> {
+        let _guard = self.evicting.lock().unwrap();
+
+        let mut entries = vec![];
+        for engine_dir in fs::read_dir(&self.dir)? {
+            let engine_dir = engine_dir?.path();
+            if !engine_dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&engine_dir)? {
+                let path = entry?.path();
+                if path.extension().is_none_or(|ext| ext != ENTRY_EXTENSION) {
+                    continue;
+                }
+                let meta = fs::metadata(&path)?;
+                entries.push((meta.modified()?, meta.len(), path));
+            }
+        }
+
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort_unstable_by_key(|(mtime, ..)| *mtime);
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            fs::remove_file(&path)?;
+            size -= len;
+            // Drop dirs left behind by previous wasmtime versions or engine configs.
+            if let Some(dir) = path.parent() {
+                let _ = fs::remove_dir(dir);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::Config;
+
+    use super::*;
+
+    // An empty component.
+    const COMPONENT: &[u8] = b"\0asm\x0d\0\x01\0";
+
+    fn cache(name: &str, max_size: u64) -> ComponentCache {
+        let path = std::env::temp_dir().join(format!("psh-cache-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        ComponentCache::new(&ComponentCacheConfig {
+            path: path.to_string_lossy().to_string(),
+            max_size,
+        })
+        .unwrap()
+    }
+
+    fn engine(fuel: bool) -> Engine {
+        let mut config = Config::new();
+        config.wasm_component_model(true);
+        config.consume_fuel(fuel);
+        Engine::new(&config).unwrap()
+    }
+
+    #[test]
+    fn reuse_entry() {
+        let cache = cache("reuse", u64::MAX);
+        let engine = engine(false);
+        cache.load_or_compile(&engine, COMPONENT).unwrap();
+        let path = cache.entry_path(&engine, COMPONENT);
+        assert!(path.exists());
+        cache.load_or_compile(&engine, COMPONENT).unwrap();
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn key_on_engine_config() {
+        let cache = cache("key", u64::MAX);
+        assert_ne!(
+            cache.entry_path(&engine(false), COMPONENT),
+            cache.entry_path(&engine(true), COMPONENT),
+        );
+    }
+
+    #[test]
+    fn recompile_corrupted_entry() {
+        let cache = cache("corrupted", u64::MAX);
+        let engine = engine(false);
+        let path = cache.entry_path(&engine, COMPONENT);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"garbage").unwrap();
+        cache.load_or_compile(&engine, COMPONENT).unwrap();
+        assert_ne!(fs::read(&path).unwrap(), b"garbage");
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+
+    #[test]
+    fn evict_above_max_size() {
+        let cache = cache("evict", 0);
+        let engine = engine(false);
+        cache.load_or_compile(&engine, COMPONENT).unwrap();
+        assert!(!cache.entry_path(&engine, COMPONENT).exists());
+        fs::remove_dir_all(&cache.dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use host_op_system::SystemInterfaces;
+use serde::{Deserialize, Serialize};
+
+/// Host interfaces and paths a task is allowed to use.
+///
+/// Deserialized from a manifest listing what is granted, anything not listed is denied:
+///
+/// ```toml
+/// interfaces = ["system/cpu", "system/memory", "data-export"]
+/// fs = ["/proc"]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "Manifest", into = "Manifest")]
+pub struct Capabilities {
+    pub perf: bool,
+    pub system: SystemInterfaces,
+    pub data_export: bool,
+    /// paths preopened read-only for the guest
+    pub fs: Vec<String>,
+}
+
+impl Capabilities {
+    /// Everything granted, which is how tasks ran before manifests existed.
+    pub fn all() -> Self {
+        Self {
+            perf: true,
+            system: SystemInterfaces::all(),
+            data_export: true,
+            fs: vec!["/".to_string()],
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    #[serde(default)]
+    interfaces: Vec<String>,
+    #[serde(default)]
+    fs: Vec<String>,
+}
+
+impl TryFrom<Manifest> for Capabilities {
+    type Error = String;
+
+    fn try_from(manifest: Manifest) -> Result<Self, Self::Error> {
+        let mut caps = Self {
+            perf: false,
+            system: SystemInterfaces::none(),
+            data_export: false,
+            fs: manifest.fs,
+        };
+        for interface in &manifest.interfaces {
+            let system = &mut caps.system;
+            match interface.as_str() {
+                "perf" => caps.perf = true,
+                "data-export" => caps.data_export = true,
+                // Every system interface but environ, which needs its own entry.
+                "system" => {
+                    *system = SystemInterfaces {
+                        process_environ: system.process_environ,
+                        ..SystemInterfaces::all()
+                    }
+                }
+                "system/os" => system.os = true,
+                "system/cpu" => system.cpu = true,
+                "system/disk" => system.disk = true,
+                "system/interrupt" => system.interrupt = true,
+                "system/memory" => system.memory = true,
+                "system/network" => system.network = true,
+                "system/process" => system.process = true,
+                "system/process/environ" => {
+                    system.process = true;
+                    system.process_environ = true;
+                }
+                "system/rps" => system.rps = true,
+                "system/vmstat" => system.vmstat = true,
+                other => return Err(format!("unknown interface `{}`", other)),
+            }
+        }
+        if let Some(path) = caps.fs.iter().find(|p| !p.starts_with('/')) {
+            return Err(format!("fs path `{}` is not absolute", path));
+        }
+        Ok(caps)
+    }
+}
+
+impl From<Capabilities> for Manifest {
+    fn from(caps: Capabilities) -> Self {
+        let system = caps.system;
+        let interfaces = [
+            ("perf", caps.perf),
+            ("data-export", caps.data_export),
+            ("system/os", system.os),
+            ("system/cpu", system.cpu),
+            ("system/disk", system.disk),
+            ("system/interrupt", system.interrupt),
+            ("system/memory", system.memory),
+            ("system/network", system.network),
+            ("system/process", system.process),
+            ("system/process/environ", system.process_environ),
+            ("system/rps", system.rps),
+            ("system/vmstat", system.vmstat),
+        ];
+        Self {
+            interfaces: interfaces
+                .into_iter()
+                .filter(|(_, granted)| *granted)
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            fs: caps.fs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Capabilities, toml::de::Error> {
+        toml::from_str(s)
+    }
+
+    #[test]
+    fn unlisted_is_denied() {
+        let caps = parse(r#"interfaces = ["system/cpu", "data-export"]"#).unwrap();
+        assert!(!caps.perf);
+        assert!(caps.data_export);
+        assert!(caps.system.cpu);
+        assert!(!caps.system.process);
+        assert!(caps.fs.is_empty());
+    }
+
+    #[test]
+    fn environ_needs_explicit_grant() {
+        let caps = parse(r#"interfaces = ["system/process"]"#).unwrap();
+        assert!(caps.system.process);
+        assert!(!caps.system.process_environ);
+
+        let caps = parse(r#"interfaces = ["system/process/environ"]"#).unwrap();
+        assert!(caps.system.process_environ);
+
+        let caps = parse(r#"interfaces = ["system"]"#).unwrap();
+        assert!(caps.system.process);
+        assert!(!caps.system.process_environ);
+
+        let caps = parse(r#"interfaces = ["system/process/environ", "system"]"#).unwrap();
+        assert!(caps.system.process_environ);
+    }
+
+    #[test]
+    fn round_trip() {
+        let caps = parse(r#"interfaces = ["perf", "system/process/environ"]"#).unwrap();
+        let manifest = toml::to_string(&caps).unwrap();
+        assert_eq!(parse(&manifest).unwrap(), caps);
+        assert_eq!(
+            parse(&toml::to_string(&Capabilities::all()).unwrap()).unwrap(),
+            Capabilities::all()
+        );
+    }
+
+    #[test]
+    fn reject_invalid_manifest() {
+        assert!(parse(r#"interfaces = ["system/gpu"]"#).is_err());
+        assert!(parse(r#"fs = ["proc"]"#).is_err());
+    }
+}
@@ -14,10 +14,11 @@
 
 use std::{
     sync::{
-        Arc,
-        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
     },
-    thread::{self, JoinHandle},
+    thread::{self, JoinHandle, Thread},
+    time::{Duration, Instant},
 };
 
 use crossbeam::queue::SegQueue;
@@ -26,10 +27,17 @@ use profiling::data_export::{
     common::FieldValue as WitFieldValue, measurement::Point, metric::Sample,
 };
 use prost::Message;
-use psh_proto::{Data, DataType, ExportDataReq};
+use psh_proto::{Data, DataType};
+use serde::Deserialize;
 use wasmtime::component::Linker;
 
-use crate::{TOKIO_RUNTIME, services::rpc::RpcClient};
+use super::{
+    distribution::Distributions,
+    outcome::ExportCounts,
+    series::{SeriesGuard, SeriesLimits},
+    sink::Sink,
+};
+use crate::otlp::GuestMetrics;
 
 wasmtime::component::bindgen!({
     path: "psh-sdk-wit/wit/deps/data-export",
@@ -55,55 +63,105 @@ impl FieldValue for WitFieldValue {
     }
 }
 
+/// `buf_size` of the config template, smaller buffers overflow easily.
+pub const RECOMMENDED_BUF_SIZE: usize = 16 * 1024 * 1024;
+
+/// What [`DataExporter::schedule`] does once the buffer holds `buf_size` bytes.
+/// Without a policy configured the buffer is unbounded, as it used to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait in the host call until the exporter has made room, or until the
+    /// task is cancelled or out of time.
+    Block,
+    /// Refuse the item with an error returned to the guest.
+    Error,
+    /// Accept and discard the item, a warning is logged.
+    Drop,
+}
+
+impl WitFieldValue {
+    /// Numeric value for metrics, booleans count as 0 and 1.
+    pub(super) const fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::Float(x) => Some(x),
+            Self::Int(x) => Some(x as f64),
+            Self::Uint(x) => Some(x as f64),
+            Self::Boolean(b) => Some(b as u8 as f64),
+            Self::Text(_) | Self::NsTs(_) => None,
+        }
+    }
+}
+
 pub struct DataExporter {
+    /// queued bytes plus those of the batch being exported
     bytes_len: Arc<AtomicUsize>,
+    bytes_capacity: usize,
     bytes_watermark: usize,
+    overflow: OverflowPolicy,
+    /// notified whenever the exporter thread has freed some bytes
+    drained: Arc<(Mutex<()>, Condvar)>,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+    /// a blocked export gives up past it
+    deadline: Option<Instant>,
+    /// set by [`DataExporter::abort`]
+    aborted: AtomicBool,
     data_queue: Arc<SegQueue<Option<Data>>>,
     task_id: String,
-    exporter: JoinHandle<()>,
+    /// taken by [`DataExporter::close`]
+    exporter: Mutex<Option<JoinHandle<()>>>,
+    thread: Thread,
 }
 
 impl DataExporter {
     pub fn new(
         bytes_capacity: usize,
         bytes_watermark: usize,
+        overflow: OverflowPolicy,
+        flush_interval: Option<Duration>,
         task_id: String,
-        rpc_client: RpcClient,
+        sink: Arc<dyn Sink>,
     ) -> Self {
-        // TODO: `bytes_capacity` is not used because we not have a static allocation
-        // for `Data`, maybe we will remove this in the future
-        let _ = bytes_capacity;
         let data_queue = Arc::new(SegQueue::<Option<Data>>::new());
         let bytes_len = Arc::new(AtomicUsize::new(0));
+        let drained = Arc::new((Mutex::new(()), Condvar::new()));
 
         let exporter = thread::spawn({
             let data_queue = Arc::clone(&data_queue);
             let bytes_len = Arc::clone(&bytes_len);
+            let drained = Arc::clone(&drained);
             let task_id = task_id.clone();
             move || {
                 let mut data = Vec::new();
+                let mut data_len = 0;
                 loop {
                     match data_queue.pop() {
                         Some(Some(o)) => {
-                            // No critical section, relaxed ordering is fine.
-                            bytes_len.fetch_sub(o.encoded_len(), Ordering::Relaxed);
+                            data_len += o.encoded_len();
                             data.push(o);
                         }
                         poped => {
                             if !data.is_empty() {
-                                let merged = ExportDataReq {
-                                    task_id: task_id.clone(),
-                                    data: data.clone(),
-                                };
-
-                                let mut rpc_client = rpc_client.clone();
-                                TOKIO_RUNTIME.block_on(async move {
-                                    let _ = rpc_client.export_data(merged).await;
-                                });
-                                data.clear();
+                                let merged = std::mem::take(&mut data);
+                                if let Err(e) = sink.export(&task_id, merged) {
+                                    tracing::warn!(
+                                        "Failed to export data of task {}: {:#}",
+                                        task_id,
+                                        e
+                                    );
+                                }
+                                // No critical section, relaxed ordering is fine.
+                                bytes_len.fetch_sub(data_len, Ordering::Relaxed);
+                                data_len = 0;
+                                drained.1.notify_all();
                             }
                             match poped {
-                                None => thread::park(),
+                                None => match flush_interval {
+                                    // Whatever arrived in the meantime is exported on wake up.
+                                    Some(interval) => thread::park_timeout(interval),
+                                    None => thread::park(),
+                                },
                                 Some(None) => break,
                                 _ => unreachable!(),
                             }
@@ -114,34 +172,132 @@ impl DataExporter {
         });
 
         Self {
+            thread: exporter.thread().clone(),
             bytes_len,
+            bytes_capacity,
             bytes_watermark,
+            overflow,
+            drained,
+            dropped: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            deadline: None,
+            aborted: AtomicBool::new(false),
             data_queue,
             task_id,
-            exporter,
+            exporter: Mutex::new(Some(exporter)),
+        }
+    }
+
+    /// Blocked exports fail past `deadline`, the end time of the task.
+    pub const fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Fail the exports blocked on a full buffer, now and from then on, the
+    /// task is being cancelled.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::Relaxed);
+        self.drained.1.notify_all();
+    }
+
+    /// Number of items and bytes waiting to be exported.
+    pub fn queued(&self) -> (usize, usize) {
+        (
+            self.data_queue.len(),
+            self.bytes_len.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn counts(&self) -> ExportCounts {
+        ExportCounts {
+            dropped: self.dropped.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
         }
     }
 
     pub fn flush(&self) {
-        self.exporter.thread().unpark();
+        self.thread.unpark();
+    }
+
+    /// Export everything still buffered and wait for the exporter thread to stop.
+    pub fn close(&self) {
+        let exporter = self.exporter.lock().unwrap().take();
+        if let Some(exporter) = exporter {
+            self.data_queue.push(None);
+            self.thread.unpark();
+            if exporter.join().is_err() {
+                tracing::error!("Data exporter of task {} has panicked", self.task_id);
+            }
+        }
     }
 
-    pub fn schedule(&self, data: Data) {
+    /// Take `len` bytes of the buffer if they fit. A single item larger than
+    /// the whole buffer still gets through an empty one.
+    fn try_reserve(&self, len: usize) -> bool {
+        self.bytes_len
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |cur| {
+                (cur == 0 || cur + len <= self.bytes_capacity).then_some(cur + len)
+            })
+            .is_ok()
+    }
+
+    pub fn schedule(&self, data: Data) -> Result<(), String> {
         let encoded_len = data.encoded_len();
+        if !self.try_reserve(encoded_len) {
+            match self.overflow {
+                OverflowPolicy::Block => {
+                    let (lock, drained) = &*self.drained;
+                    let mut guard = lock.lock().unwrap();
+                    while !self.try_reserve(encoded_len) {
+                        // A guest waiting here is not interrupted by the epoch.
+                        let expired = self.deadline.is_some_and(|it| Instant::now() >= it);
+                        if expired || self.aborted.load(Ordering::Relaxed) {
+                            self.rejected.fetch_add(1, Ordering::Relaxed);
+                            return Err(
+                                "Data export buffer is full and the task is ending".to_string()
+                            );
+                        }
+                        self.flush();
+                        // The exporter does not take the lock, do not miss its notification.
+                        guard = drained
+                            .wait_timeout(guard, Duration::from_millis(100))
+                            .unwrap()
+                            .0;
+                    }
+                    drop(guard);
+                }
+                OverflowPolicy::Error => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Err("Data export buffer is full".to_string());
+                }
+                OverflowPolicy::Drop => {
+                    let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                    tracing::warn!(
+                        "Data export buffer of task {} is full, dropped {} bytes, {} items so far",
+                        self.task_id,
+                        encoded_len,
+                        dropped
+                    );
+                    return Ok(());
+                }
+            }
+        }
         self.data_queue.push(Some(data));
-        // No critical section, relaxed ordering is fine.
-        let prev = self.bytes_len.fetch_add(encoded_len, Ordering::Relaxed);
-        if prev > self.bytes_watermark {
-            self.exporter.thread().unpark();
+        if self.bytes_len.load(Ordering::Relaxed) > self.bytes_watermark {
+            self.thread.unpark();
         }
+        Ok(())
     }
 }
 
 impl Drop for DataExporter {
     fn drop(&mut self) {
-        // Notify the consumer that there is no more data.
-        self.data_queue.push(None);
-        self.exporter.thread().unpark();
+        // Notify the consumer that there is no more data, unless it is already closed.
+        if self.exporter.get_mut().unwrap().is_some() {
+            self.data_queue.push(None);
+            self.thread.unpark();
+        }
     }
 }
 
@@ -151,13 +307,87 @@ pub struct Ctx {
     pub exporter: Arc<DataExporter>,
 }
 
+impl Ctx {
+    /// Export a line the guest wrote to `stream` as a `psh_task_log` point.
+    pub fn export_log(&self, stream: &str, line: &str) {
+        let bytes = LineProtocolBuilder::new()
+            .measurement("psh_task_log")
+            .tag("task_id", &self.exporter.task_id)
+            .tag("instance_id", &self.instance_id)
+            .tag("stream", stream)
+            .field("message", line)
+            .close_line()
+            .build();
+        // Counted like any other item if the buffer is full.
+        let _ = self.exporter.schedule(Data {
+            ty: DataType::LineProtocol as _,
+            bytes,
+        });
+    }
+
+    /// Export a point the host built for the task, tagged like the guest ones.
+    pub fn export_fields(
+        &self,
+        name: &str,
+        tags: &[(String, String)],
+        fields: Vec<(String, WitFieldValue)>,
+    ) -> Result<(), String> {
+        let lp = LineProtocolBuilder::new().measurement(name);
+        let lp = tags.iter().fold(lp, |lp, (k, v)| lp.tag(k, v));
+        let lp = lp
+            .tag("task_id", &self.exporter.task_id)
+            .tag("instance_id", &self.instance_id);
+
+        let mut fields = fields.into_iter();
+        let Some((first_key, first_val)) = fields.next() else {
+            return Err("No fields provided in point".to_string());
+        };
+        let lp = lp.field::<WitFieldValue>(&first_key, first_val);
+        let lp = fields.fold(lp, |lp, (k, v)| lp.field::<WitFieldValue>(&k, v));
+
+        self.exporter.schedule(Data {
+            ty: DataType::LineProtocol as _,
+            bytes: lp.close_line().build(),
+        })
+    }
+}
+
+/// Where the samples and points of a task are re-emitted as OTLP metrics.
 #[derive(Clone)]
+pub struct TaskMetrics {
+    pub task_id: String,
+    pub bridge: Arc<GuestMetrics>,
+}
+
 pub struct DataExportCtx {
     pub ctx: Option<Ctx>,
+    pub metrics: Option<TaskMetrics>,
+    /// histograms and summaries registered by the task
+    pub distributions: Distributions,
+    pub series: SeriesGuard,
+}
+
+impl DataExportCtx {
+    pub fn new(ctx: Option<Ctx>, metrics: Option<TaskMetrics>, limits: SeriesLimits) -> Self {
+        Self {
+            ctx,
+            metrics,
+            distributions: Distributions::default(),
+            series: SeriesGuard::new(limits),
+        }
+    }
+}
+
+impl Drop for DataExportCtx {
+    fn drop(&mut self) {
+        // The exporter is closed once the task is over, it still takes these.
+        self.flush_distributions();
+    }
 }
 
 impl profiling::data_export::common::Host for DataExportCtx {
     fn flush_buf(&mut self) -> wasmtime::Result<Result<(), String>> {
+        self.flush_distributions();
         if let Some(ctx) = &mut self.ctx {
             ctx.exporter.flush();
         }
@@ -175,14 +405,22 @@ impl profiling::data_export::file::Host for DataExportCtx {
             ty: DataType::File as _,
             bytes,
         };
-        ctx.exporter.schedule(data);
-
-        Ok(Ok(()))
+        Ok(ctx.exporter.schedule(data))
     }
 }
 
 impl profiling::data_export::metric::Host for DataExportCtx {
     fn export_sample(&mut self, mut sample: Sample) -> wasmtime::Result<Result<(), String>> {
+        if let Err(e) = self.series.check(&sample.name, &mut sample.tags, ["value"]) {
+            return Ok(Err(e));
+        }
+        if let Some(metrics) = &self.metrics {
+            if let Some(value) = sample.value.as_f64() {
+                metrics
+                    .bridge
+                    .record(&metrics.task_id, &sample.name, &sample.tags, value);
+            }
+        }
         let Some(ctx) = &mut self.ctx else {
             return Ok(Ok(()));
         };
@@ -204,14 +442,26 @@ impl profiling::data_export::metric::Host for DataExportCtx {
             ty: DataType::LineProtocol as _,
             bytes,
         };
-        ctx.exporter.schedule(data);
-
-        Ok(Ok(()))
+        Ok(ctx.exporter.schedule(data))
     }
 }
 
 impl profiling::data_export::measurement::Host for DataExportCtx {
     fn export_point(&mut self, mut point: Point) -> wasmtime::Result<Result<(), String>> {
+        let fields = point.fields.iter().map(|(k, _)| k.as_str());
+        if let Err(e) = self.series.check(&point.name, &mut point.tags, fields) {
+            return Ok(Err(e));
+        }
+        if let Some(metrics) = &self.metrics {
+            for (field, value) in &point.fields {
+                if let Some(value) = value.as_f64() {
+                    let name = format!("{}.{}", point.name, field);
+                    metrics
+                        .bridge
+                        .record(&metrics.task_id, &name, &point.tags, value);
+                }
+            }
+        }
         let Some(ctx) = &mut self.ctx else {
             return Ok(Ok(()));
         };
@@ -241,9 +491,7 @@ impl profiling::data_export::measurement::Host for DataExportCtx {
             ty: DataType::LineProtocol as _,
             bytes,
         };
-        ctx.exporter.schedule(data);
-
-        Ok(Ok(()))
+        Ok(ctx.exporter.schedule(data))
     }
 }
 
@@ -253,3 +501,145 @@ pub fn add_to_linker<T>(
 ) -> anyhow::Result<()> {
     Imports::add_to_linker(l, f)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicBool, time::Instant};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct GatedSink {
+        open: AtomicBool,
+        received: AtomicUsize,
+    }
+
+    impl Sink for GatedSink {
+        fn export(&self, _: &str, data: Vec<Data>) -> anyhow::Result<()> {
+            // Bytes of a batch stay reserved until it is exported.
+            while !self.open.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            self.received.fetch_add(data.len(), Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    fn data() -> Data {
+        Data {
+            ty: DataType::LineProtocol as _,
+            bytes: b"m v=1\n".to_vec(),
+        }
+    }
+
+    fn exporter(overflow: OverflowPolicy, sink: Arc<GatedSink>) -> DataExporter {
+        // Room for two items, never flushed on the watermark.
+        let capacity = data().encoded_len() * 2;
+        DataExporter::new(capacity, usize::MAX, overflow, None, "42".to_string(), sink)
+    }
+
+    #[test]
+    fn reject_or_drop_when_full() {
+        let sink = Arc::new(GatedSink::default());
+        let exporter = self::exporter(OverflowPolicy::Error, sink.clone());
+        assert!(exporter.schedule(data()).is_ok());
+        assert!(exporter.schedule(data()).is_ok());
+        assert!(exporter.schedule(data()).is_err());
+
+        let exporter = self::exporter(OverflowPolicy::Drop, sink.clone());
+        for _ in 0..3 {
+            assert!(exporter.schedule(data()).is_ok());
+        }
+        assert_eq!(exporter.queued().1, data().encoded_len() * 2);
+        assert_eq!(
+            exporter.counts(),
+            ExportCounts {
+                dropped: 1,
+                rejected: 0
+            }
+        );
+        sink.open.store(true, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn block_until_exported() {
+        let sink = Arc::new(GatedSink::default());
+        sink.open.store(true, Ordering::Relaxed);
+        let exporter = self::exporter(OverflowPolicy::Block, sink.clone());
+        for _ in 0..10 {
+            exporter.schedule(data()).unwrap();
+        }
+        exporter.flush();
+
+        let start = Instant::now();
+        while sink.received.load(Ordering::Relaxed) < 10 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(exporter.counts(), ExportCounts::default());
+    }
+
+    #[test]
+    fn stop_blocking_when_aborted() {
+        let sink = Arc::new(GatedSink::default());
+        let exporter = Arc::new(self::exporter(OverflowPolicy::Block, sink.clone()));
+        exporter.schedule(data()).unwrap();
+        exporter.schedule(data()).unwrap();
+        let blocked = thread::spawn({
+            let exporter = exporter.clone();
+            move || exporter.schedule(data())
+        });
+        thread::sleep(Duration::from_millis(50));
+        exporter.abort();
+        assert!(blocked.join().unwrap().is_err());
+        assert_eq!(exporter.counts().rejected, 1);
+
+        let exporter = self::exporter(OverflowPolicy::Block, sink.clone())
+            .with_deadline(Instant::now() + Duration::from_millis(50));
+        exporter.schedule(data()).unwrap();
+        exporter.schedule(data()).unwrap();
+        assert!(exporter.schedule(data()).is_err());
+        sink.open.store(true, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn flush_on_interval() {
+        let sink = Arc::new(GatedSink::default());
+        sink.open.store(true, Ordering::Relaxed);
+        let exporter = DataExporter::new(
+            usize::MAX,
+            usize::MAX,
+            OverflowPolicy::Block,
+            Some(Duration::from_millis(10)),
+            "42".to_string(),
+            sink.clone(),
+        );
+        exporter.schedule(data()).unwrap();
+
+        let start = Instant::now();
+        while sink.received.load(Ordering::Relaxed) < 1 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn close_drains_buffer() {
+        let sink = Arc::new(GatedSink::default());
+        sink.open.store(true, Ordering::Relaxed);
+        let exporter = DataExporter::new(
+            usize::MAX,
+            usize::MAX,
+            OverflowPolicy::Block,
+            None,
+            "42".to_string(),
+            sink.clone(),
+        );
+        for _ in 0..3 {
+            exporter.schedule(data()).unwrap();
+        }
+        exporter.close();
+        assert_eq!(sink.received.load(Ordering::Relaxed), 3);
+        assert_eq!(exporter.queued(), (0, 0));
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use opentelemetry::{KeyValue, metrics::Histogram as OtlpHistogram};
+use profiling::distribution::{histogram, summary};
+use wasmtime::component::Linker;
+
+use super::data_export::{Ctx, DataExportCtx, profiling::data_export::common::FieldValue};
+
+wasmtime::component::bindgen!({
+    // Not in psh-sdk-wit yet, guests get the package from this repository.
+    path: "wit/distribution",
+    world: "imports",
+    trappable_imports: true,
+});
+
+/// Instruments of each kind a task can register, they live as long as the task.
+const MAX_INSTRUMENTS: usize = 1024;
+/// Bounds of a histogram or quantiles of a summary.
+const MAX_POINTS: usize = 256;
+/// Observations a summary samples between two flushes to estimate its quantiles.
+const RESERVOIR_SIZE: usize = 1024;
+
+type Tags = Vec<(String, String)>;
+
+struct Histogram {
+    name: String,
+    /// sorted
+    tags: Tags,
+    bounds: Vec<f64>,
+    /// one more than the bounds, the last one is above all of them
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+    /// observed since the last flush
+    dirty: bool,
+    otlp: Option<(OtlpHistogram<f64>, Vec<KeyValue>)>,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += value;
+        self.dirty = true;
+        if let Some((histogram, attrs)) = &self.otlp {
+            histogram.record(value, attrs);
+        }
+    }
+
+    /// Count and sum, then the observations up to each bound, like the `le`
+    /// buckets of Prometheus.
+    fn fields(&self) -> Vec<(String, FieldValue)> {
+        let mut fields = vec![
+            ("count".to_string(), FieldValue::Uint(self.count)),
+            ("sum".to_string(), FieldValue::Float(self.sum)),
+        ];
+        let bounds = self
+            .bounds
+            .iter()
+            .map(f64::to_string)
+            .chain(["+Inf".to_string()]);
+        let mut cumulative = 0;
+        for (bound, count) in bounds.zip(&self.buckets) {
+            cumulative += count;
+            fields.push((bound, FieldValue::Uint(cumulative)));
+        }
+        fields
+    }
+}
+
+struct Summary {
+    name: String,
+    /// sorted
+    tags: Tags,
+    quantiles: Vec<f64>,
+    count: u64,
+    sum: f64,
+    /// uniform sample of the observations since the last flush
+    reservoir: Vec<f64>,
+    /// observations since the last flush
+    seen: u64,
+    rng: u64,
+}
+
+impl Summary {
+    fn observe(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.seen += 1;
+        if self.reservoir.len() < RESERVOIR_SIZE {
+            self.reservoir.push(value);
+            return;
+        }
+        // Algorithm R, the value replaces a kept one with probability RESERVOIR_SIZE / seen.
+        let slot = self.next_random() % self.seen;
+        if let Some(kept) = self.reservoir.get_mut(slot as usize) {
+            *kept = value;
+        }
+    }
+
+    /// xorshift64
+    const fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    /// Nearest rank estimate of the quantiles, sampling starts over afterwards.
+    fn take_quantiles(&mut self) -> Vec<(f64, f64)> {
+        let sample = &mut self.reservoir;
+        sample.sort_unstable_by(f64::total_cmp);
+        let quantiles = if sample.is_empty() {
+            vec![]
+        } else {
+            self.quantiles
+                .iter()
+                .map(|&q| {
+                    let rank = (q * sample.len() as f64).ceil() as usize;
+                    (q, sample[rank.clamp(1, sample.len()) - 1])
+                })
+                .collect()
+        };
+        sample.clear();
+        self.seen = 0;
+        quantiles
+    }
+}
+
+/// Histograms and summaries of a task, in registration order.
+#[derive(Default)]
+pub struct Distributions {
+    histograms: Vec<Histogram>,
+    summaries: Vec<Summary>,
+}
+
+fn export(ctx: &Ctx, name: &str, tags: &[(String, String)], fields: Vec<(String, FieldValue)>) {
+    // Rejected points show up in the export counts of the task.
+    let _ = ctx.export_fields(name, tags, fields);
+}
+
+impl DataExportCtx {
+    /// Export the instruments observed since the last flush.
+    pub(super) fn flush_distributions(&mut self) {
+        let Self {
+            ctx,
+            metrics,
+            distributions,
+            ..
+        } = self;
+        for histogram in distributions.histograms.iter_mut().filter(|it| it.dirty) {
+            histogram.dirty = false;
+            if let Some(ctx) = ctx {
+                export(ctx, &histogram.name, &histogram.tags, histogram.fields());
+            }
+        }
+        for summary in distributions.summaries.iter_mut().filter(|it| it.seen > 0) {
+            let quantiles = summary.take_quantiles();
+            if let Some(metrics) = metrics {
+                for (quantile, value) in &quantiles {
+                    let mut tags = summary.tags.clone();
+                    tags.push(("quantile".to_string(), quantile.to_string()));
+                    metrics
+                        .bridge
+                        .record(&metrics.task_id, &summary.name, &tags, *value);
+                }
+            }
+            if let Some(ctx) = ctx {
+                let fields = [
+                    ("count".to_string(), FieldValue::Uint(summary.count)),
+                    ("sum".to_string(), FieldValue::Float(summary.sum)),
+                ]
+                .into_iter()
+                .chain(
+                    quantiles
+                        .into_iter()
+                        .map(|(q, v)| (q.to_string(), FieldValue::Float(v))),
+                )
+                .collect();
+                export(ctx, &summary.name, &summary.tags, fields);
+            }
+        }
+    }
+}
+
+fn check_registration(registered: usize, points: usize) -> Result<(), String> {
+    if registered >= MAX_INSTRUMENTS {
+        return Err(format!("At most {} instruments of a kind", MAX_INSTRUMENTS));
+    }
+    if points > MAX_POINTS {
+        return Err(format!("At most {} bounds or quantiles", MAX_POINTS));
+    }
+    Ok(())
+}
+
+impl histogram::Host for DataExportCtx {
+    fn register(
+        &mut self,
+        name: String,
+        mut tags: Tags,
+        bounds: Vec<f64>,
+    ) -> wasmtime::Result<Result<u32, String>> {
+        if let Err(e) = self.series.check(&name, &mut tags, []) {
+            return Ok(Err(e));
+        }
+        tags.sort_unstable();
+        let histograms = &mut self.distributions.histograms;
+        if let Some(id) = histograms
+            .iter()
+            .position(|it| it.name == name && it.tags == tags)
+        {
+            return Ok(if histograms[id].bounds == bounds {
+                Ok(id as u32)
+            } else {
+                Err(format!("Histogram {} has other bounds", name))
+            });
+        }
+        if let Err(e) = check_registration(histograms.len(), bounds.len()) {
+            return Ok(Err(e));
+        }
+        if bounds.iter().any(|it| !it.is_finite()) || bounds.windows(2).any(|it| it[0] >= it[1]) {
+            return Ok(Err(
+                "Bounds must be finite and strictly increasing".to_string()
+            ));
+        }
+
+        let otlp = match &self.metrics {
+            Some(metrics) => match metrics.bridge.histogram(&name, &bounds) {
+                Ok(histogram) => histogram
+                    .map(|histogram| (histogram, metrics.bridge.histogram_attributes(&tags))),
+                Err(e) => return Ok(Err(e)),
+            },
+            None => None,
+        };
+        histograms.push(Histogram {
+            name,
+            tags,
+            buckets: vec![0; bounds.len() + 1],
+            bounds,
+            count: 0,
+            sum: 0.0,
+            dirty: false,
+            otlp,
+        });
+        Ok(Ok(histograms.len() as u32 - 1))
+    }
+
+    fn observe(&mut self, histogram: u32, value: f64) -> wasmtime::Result<Result<(), String>> {
+        if value.is_nan() {
+            return Ok(Err("Cannot observe NaN".to_string()));
+        }
+        let Some(histogram) = self.distributions.histograms.get_mut(histogram as usize) else {
+            return Ok(Err(format!("Unknown histogram {}", histogram)));
+        };
+        histogram.observe(value);
+        Ok(Ok(()))
+    }
+}
+
+impl summary::Host for DataExportCtx {
+    fn register(
+        &mut self,
+        name: String,
+        mut tags: Tags,
+        quantiles: Vec<f64>,
+    ) -> wasmtime::Result<Result<u32, String>> {
+        if let Err(e) = self.series.check(&name, &mut tags, []) {
+            return Ok(Err(e));
+        }
+        tags.sort_unstable();
+        let summaries = &mut self.distributions.summaries;
+        if let Some(id) = summaries
+            .iter()
+            .position(|it| it.name == name && it.tags == tags)
+        {
+            return Ok(if summaries[id].quantiles == quantiles {
+                Ok(id as u32)
+            } else {
+                Err(format!("Summary {} has other quantiles", name))
+            });
+        }
+        if let Err(e) = check_registration(summaries.len(), quantiles.len()) {
+            return Ok(Err(e));
+        }
+        if quantiles.iter().any(|it| !(0.0..=1.0).contains(it)) {
+            return Ok(Err("Quantiles must be within [0, 1]".to_string()));
+        }
+
+        summaries.push(Summary {
+            name,
+            tags,
+            quantiles,
+            count: 0,
+            sum: 0.0,
+            reservoir: Vec::new(),
+            seen: 0,
+            rng: 0x9e37_79b9_7f4a_7c15,
+        });
+        Ok(Ok(summaries.len() as u32 - 1))
+    }
+
+    fn observe(&mut self, summary: u32, value: f64) -> wasmtime::Result<Result<(), String>> {
+        if value.is_nan() {
+            return Ok(Err("Cannot observe NaN".to_string()));
+        }
+        let Some(summary) = self.distributions.summaries.get_mut(summary as usize) else {
+            return Ok(Err(format!("Unknown summary {}", summary)));
+        };
+        summary.observe(value);
+        Ok(Ok(()))
+    }
+}
+
+pub fn add_to_linker<T>(
+    l: &mut Linker<T>,
+    f: impl (Fn(&mut T) -> &mut DataExportCtx) + Copy + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    Imports::add_to_linker(l, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runtime::series::SeriesLimits;
+
+    fn tags() -> Tags {
+        vec![("op".to_string(), "read".to_string())]
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let mut ctx = DataExportCtx::new(None, None, SeriesLimits::default());
+        let id = histogram::Host::register(&mut ctx, "lat".to_string(), tags(), vec![1.0, 5.0])
+            .unwrap()
+            .unwrap();
+        for value in [0.5, 1.0, 3.0, 10.0] {
+            histogram::Host::observe(&mut ctx, id, value)
+                .unwrap()
+                .unwrap();
+        }
+
+        let fields: Vec<_> = ctx.distributions.histograms[0]
+            .fields()
+            .into_iter()
+            .map(|(k, v)| (k, v.as_f64().unwrap()))
+            .collect();
+        let expected = [
+            ("count", 4.0),
+            ("sum", 14.5),
+            ("1", 2.0),
+            ("5", 3.0),
+            ("+Inf", 4.0),
+        ];
+        assert_eq!(fields.len(), expected.len());
+        for ((k, v), (expected_k, expected_v)) in fields.iter().zip(expected) {
+            assert_eq!((k.as_str(), *v), (expected_k, expected_v));
+        }
+
+        ctx.flush_distributions();
+        assert!(!ctx.distributions.histograms[0].dirty);
+    }
+
+    #[test]
+    fn histogram_registration() {
+        let mut ctx = DataExportCtx::new(None, None, SeriesLimits::default());
+        let mut register = |bounds: Vec<f64>| {
+            histogram::Host::register(&mut ctx, "lat".to_string(), tags(), bounds).unwrap()
+        };
+        assert!(register(vec![5.0, 1.0]).is_err());
+        assert!(register(vec![1.0, f64::INFINITY]).is_err());
+        assert_eq!(register(vec![1.0]), Ok(0));
+        assert_eq!(register(vec![1.0]), Ok(0));
+        assert!(register(vec![2.0]).is_err());
+        assert!(histogram::Host::observe(&mut ctx, 1, 1.0).unwrap().is_err());
+        assert!(
+            histogram::Host::observe(&mut ctx, 0, f64::NAN)
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn summary_quantiles() {
+        let mut ctx = DataExportCtx::new(None, None, SeriesLimits::default());
+        assert!(
+            summary::Host::register(&mut ctx, "lat".to_string(), tags(), vec![1.5])
+                .unwrap()
+                .is_err()
+        );
+        let id = summary::Host::register(&mut ctx, "lat".to_string(), tags(), vec![0.5, 0.99])
+            .unwrap()
+            .unwrap();
+        for value in 1..=100 {
+            summary::Host::observe(&mut ctx, id, value as f64)
+                .unwrap()
+                .unwrap();
+        }
+
+        let summary = &mut ctx.distributions.summaries[0];
+        assert_eq!(summary.take_quantiles(), [(0.5, 50.0), (0.99, 99.0)]);
+        assert!(summary.take_quantiles().is_empty());
+        assert_eq!((summary.count, summary.sum), (100, 5050.0));
+    }
+
+    #[test]
+    fn summary_reservoir_is_bounded() {
+        let mut ctx = DataExportCtx::new(None, None, SeriesLimits::default());
+        let id = summary::Host::register(&mut ctx, "lat".to_string(), tags(), vec![0.5])
+            .unwrap()
+            .unwrap();
+        for value in 0..RESERVOIR_SIZE * 4 {
+            summary::Host::observe(&mut ctx, id, value as f64)
+                .unwrap()
+                .unwrap();
+        }
+
+        let summary = &mut ctx.distributions.summaries[0];
+        assert_eq!(summary.reservoir.len(), RESERVOIR_SIZE);
+        let median = summary.take_quantiles()[0].1;
+        // Sampled uniformly, far from the first observations.
+        assert!(median > RESERVOIR_SIZE as f64);
+    }
+}
@@ -12,38 +12,133 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use std::{thread, time::Duration};
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{RecvTimeoutError, channel},
+    },
+    thread,
+    time::Duration,
+};
 
-use anyhow::Context;
+use anyhow::{Context, bail};
 use wasmtime::{
-    Engine, Store,
+    Engine, Store, Trap,
     component::{Component, Linker},
 };
-use wasmtime_wasi::bindings::sync::Command;
+use wasmtime_wasi::{I32Exit, bindings::sync::Command};
 
-use super::PshState;
+use super::{
+    PshState,
+    cache::ComponentCache,
+    limits::count_limit_exceeded,
+    outcome::{HostCallCounters, HostCalls, SetupError},
+};
 
 pub struct PshEngine {
     pub engine: Engine,
     pub store: Store<PshState>,
     pub linker: Linker<PshState>,
+    component_cache: Option<Arc<ComponentCache>>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Handle to interrupt a [`PshEngine`] from another thread.
+#[derive(Clone)]
+pub struct PshEngineHandle {
+    engine: Engine,
+    cancelled: Arc<AtomicBool>,
+    host_calls: Arc<HostCallCounters>,
+}
+
+impl PshEngineHandle {
+    /// Interrupt the running guest at its next epoch check.
+    pub fn cancel(&self) {
+        // The flag must be visible before the epoch bump, see `PshEngine::run`.
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.engine.increment_epoch();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    pub fn host_calls(&self) -> HostCalls {
+        self.host_calls.snapshot()
+    }
 }
 
 impl PshEngine {
+    pub fn new(
+        engine: Engine,
+        store: Store<PshState>,
+        linker: Linker<PshState>,
+        component_cache: Option<Arc<ComponentCache>>,
+    ) -> Self {
+        Self {
+            engine,
+            store,
+            linker,
+            component_cache,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn handle(&self) -> PshEngineHandle {
+        PshEngineHandle {
+            engine: self.engine.clone(),
+            cancelled: self.cancelled.clone(),
+            host_calls: self.store.data().host_calls.clone(),
+        }
+    }
+
     pub fn run(mut self, binary: &[u8], time_slice: u64) -> anyhow::Result<()> {
-        let component =
-            Component::from_binary(&self.engine, binary).context("Failed to load component!")?;
-        let cmd = Command::instantiate(&mut self.store, &component, &self.linker)
-            .context("Failed to instantiate Wasi Command!")?;
+        let component = match &self.component_cache {
+            Some(cache) => cache.load_or_compile(&self.engine, binary),
+            None => Component::from_binary(&self.engine, binary),
+        }
+        .context(SetupError::Load)?;
+        let cmd = match Command::instantiate(&mut self.store, &component, &self.linker) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                // Memories and tables may grow past their limits during instantiation already.
+                let exceeded = (self.store.data().limiter.exceeded().cloned())
+                    .or_else(|| count_limit_exceeded(&e));
+                return Err(match exceeded {
+                    Some(exceeded) => e.context(exceeded),
+                    None => e.context(SetupError::Instantiate),
+                });
+            }
+        };
         self.store.set_epoch_deadline(1);
+        // A cancel that bumped the epoch before the deadline was set would be missed.
+        if self.cancelled.load(Ordering::SeqCst) {
+            bail!(Trap::Interrupt);
+        }
+
+        let (done_tx, done_rx) = channel::<()>();
+        let engine = self.engine.clone();
         thread::spawn(move || {
-            thread::sleep(Duration::from_millis(time_slice as _));
-            self.engine.increment_epoch();
+            let timeout = done_rx.recv_timeout(Duration::from_millis(time_slice as _));
+            if timeout == Err(RecvTimeoutError::Timeout) {
+                engine.increment_epoch();
+            }
         });
-        let _ = cmd
-            .wasi_cli_run()
-            .call_run(&mut self.store)
-            .context("Failed to run component")?;
-        Ok(())
+        let result = cmd.wasi_cli_run().call_run(&mut self.store);
+        drop(done_tx);
+        let exceeded = self.store.data().limiter.exceeded().cloned();
+        // Release perf counters, samplers and process handles held by the guest
+        // as soon as it stops, not when the caller drops the engine.
+        drop(self.store);
+
+        // Returning an error from `run` without calling `exit` means exit code 1.
+        let result = result
+            .context("Failed to run component")
+            .and_then(|it| it.map_err(|()| I32Exit(1).into()));
+        match (result, exceeded) {
+            (Err(e), Some(exceeded)) => Err(e.context(exceeded)),
+            (result, _) => result,
+        }
     }
 }
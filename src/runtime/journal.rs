@@ -0,0 +1,291 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use chrono::{TimeZone, Utc, offset::LocalResult};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use super::{
+    Capabilities, ExportCounts, HostCalls, Task, TaskLimits, TaskOrigin, TaskOutcome, TaskStatus,
+};
+
+/// Journal location relative to the daemon workdir.
+pub const JOURNAL_DIR: &str = "psh-journal";
+const TASKS_DIR: &str = "tasks";
+const DONE_DIR: &str = "done";
+
+/// On-disk record of the remote tasks, so that neither an accepted task nor
+/// its completion is lost when psh restarts.
+///
+/// ```text
+/// <dir>/tasks/<id>.json  accepted or running task
+/// <dir>/tasks/<id>.wasm  its component
+/// <dir>/done/<id>.json   outcome not reported to the server yet
+/// ```
+pub struct Journal {
+    dir: PathBuf,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    id: String,
+    args: Vec<String>,
+    /// in milliseconds since the epoch
+    end_time: i64,
+    limits: TaskLimits,
+    capabilities: Option<Capabilities>,
+    running: bool,
+}
+
+/// What was left in the journal by the previous run.
+pub struct Recovered {
+    pub tasks: Vec<Task>,
+    pub finished: Vec<(String, TaskOutcome)>,
+}
+
+impl Journal {
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        for sub in [TASKS_DIR, DONE_DIR] {
+            fs::create_dir_all(dir.join(sub))
+                .with_context(|| format!("Failed to create journal {}", dir.display()))?;
+        }
+        Ok(Self { dir })
+    }
+
+    pub fn accepted(&self, task: &Task) -> Result<()> {
+        let Some(id) = &task.id else {
+            return Ok(());
+        };
+        write_atomic(&self.path(TASKS_DIR, id, "wasm"), &task.wasm_component)?;
+        let entry = Entry {
+            id: id.clone(),
+            args: task.wasm_component_args.clone(),
+            end_time: task.end_time.timestamp_millis(),
+            limits: task.limits.clone(),
+            capabilities: task.capabilities.clone(),
+            running: false,
+        };
+        write_json(&self.path(TASKS_DIR, id, "json"), &entry)
+    }
+
+    pub fn running(&self, id: &str) -> Result<()> {
+        let path = self.path(TASKS_DIR, id, "json");
+        let mut entry: Entry = read_json(&path)?;
+        entry.running = true;
+        write_json(&path, &entry)
+    }
+
+    /// The outcome is kept until [`Self::reported`], the task itself is dropped.
+    pub fn finished(&self, id: &str, outcome: &TaskOutcome) -> Result<()> {
+        write_json(&self.path(DONE_DIR, id, "json"), &(id, outcome))?;
+        let _ = fs::remove_file(self.path(TASKS_DIR, id, "json"));
+        let _ = fs::remove_file(self.path(TASKS_DIR, id, "wasm"));
+        Ok(())
+    }
+
+    pub fn reported(&self, id: &str) -> Result<()> {
+        fs::remove_file(self.path(DONE_DIR, id, "json"))?;
+        Ok(())
+    }
+
+    /// Tasks still before their end time are resumed from the start, including
+    /// the ones that were running, the others expire as timed out.
+    pub fn recover(&self) -> Result<Recovered> {
+        let mut recovered = Recovered {
+            tasks: vec![],
+            finished: vec![],
+        };
+
+        for path in list_json(&self.dir.join(TASKS_DIR))? {
+            let entry = match read_json::<Entry>(&path) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    tracing::warn!("Discard journal entry {}: {:#}", path.display(), e);
+                    let _ = fs::remove_file(&path);
+                    continue;
+                }
+            };
+            let wasm = fs::read(path.with_extension("wasm"));
+            let end_time = match Utc.timestamp_millis_opt(entry.end_time) {
+                LocalResult::Single(t) if t > Utc::now() => Some(t),
+                _ => None,
+            };
+            match (wasm, end_time) {
+                (Ok(wasm_component), Some(end_time)) => {
+                    if entry.running {
+                        tracing::info!("Restart task {} interrupted by psh exiting", entry.id);
+                    }
+                    recovered.tasks.push(Task {
+                        id: Some(entry.id),
+                        wasm_component,
+                        wasm_component_args: entry.args,
+                        end_time,
+                        limits: entry.limits,
+                        capabilities: entry.capabilities,
+                        origin: TaskOrigin::Remote,
+                    });
+                }
+                (wasm, _) => {
+                    let status = match wasm {
+                        Ok(_) => TaskStatus::TimedOut,
+                        Err(e) => {
+                            TaskStatus::BuildFailed(format!("Lost journaled component: {}", e))
+                        }
+                    };
+                    let outcome = TaskOutcome {
+                        status,
+                        wall_time: Duration::ZERO,
+                        host_calls: HostCalls::default(),
+                        export: ExportCounts::default(),
+                    };
+                    self.finished(&entry.id, &outcome)?;
+                }
+            }
+        }
+
+        for path in list_json(&self.dir.join(DONE_DIR))? {
+            match read_json(&path) {
+                Ok(finished) => recovered.finished.push(finished),
+                Err(e) => {
+                    tracing::warn!("Discard journal entry {}: {:#}", path.display(), e);
+                    let _ = fs::remove_file(&path);
+                }
+            }
+        }
+
+        Ok(recovered)
+    }
+
+    fn path(&self, sub: &str, id: &str, ext: &str) -> PathBuf {
+        // Task ids come from the server, keep them from escaping the journal.
+        let id = id.replace(['/', '\\'], "_");
+        self.dir.join(sub).join(format!("{}.{}", id, ext))
+    }
+}
+
+fn list_json(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "json") {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let bytes = fs::read(path)?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    write_atomic(path, &serde_json::to_vec(value)?)
+}
+
+/// Write to a temporary file first, a crash must not leave a truncated entry.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use chrono::TimeDelta;
+
+    use super::*;
+
+    fn journal(name: &str) -> Journal {
+        let dir = std::env::temp_dir().join(format!("psh-journal-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Journal::open(dir).unwrap()
+    }
+
+    fn task(id: &str, end_time: TimeDelta) -> Task {
+        Task {
+            id: Some(id.to_string()),
+            wasm_component: b"\0asm".to_vec(),
+            wasm_component_args: vec![id.to_string(), "--flag".to_string()],
+            end_time: Utc::now() + end_time,
+            limits: TaskLimits::default(),
+            capabilities: None,
+            origin: TaskOrigin::Remote,
+        }
+    }
+
+    #[test]
+    fn resume_or_expire() {
+        let journal = journal("resume");
+        journal
+            .accepted(&task("queued", TimeDelta::hours(1)))
+            .unwrap();
+        journal
+            .accepted(&task("running", TimeDelta::hours(1)))
+            .unwrap();
+        journal.running("running").unwrap();
+        journal
+            .accepted(&task("expired", -TimeDelta::hours(1)))
+            .unwrap();
+
+        let mut recovered = journal.recover().unwrap();
+        recovered.tasks.sort_by(|a, b| a.id.cmp(&b.id));
+        let ids: Vec<_> = recovered
+            .tasks
+            .iter()
+            .map(|t| t.id.clone().unwrap())
+            .collect();
+        assert_eq!(ids, ["queued", "running"]);
+        assert_eq!(recovered.tasks[0].wasm_component, b"\0asm");
+        assert_eq!(recovered.tasks[0].wasm_component_args[1], "--flag");
+
+        assert_eq!(recovered.finished.len(), 1);
+        let (id, outcome) = &recovered.finished[0];
+        assert_eq!(id, "expired");
+        assert_eq!(outcome.status, TaskStatus::TimedOut);
+        fs::remove_dir_all(&journal.dir).unwrap();
+    }
+
+    #[test]
+    fn keep_outcome_until_reported() {
+        let journal = journal("report");
+        journal.accepted(&task("a", TimeDelta::hours(1))).unwrap();
+        let outcome = TaskOutcome {
+            status: TaskStatus::Exited(2),
+            wall_time: Duration::from_millis(42),
+            host_calls: HostCalls::default(),
+            export: ExportCounts::default(),
+        };
+        journal.finished("a", &outcome).unwrap();
+
+        let recovered = journal.recover().unwrap();
+        assert!(recovered.tasks.is_empty());
+        assert_eq!(recovered.finished, [("a".to_string(), outcome)]);
+
+        journal.reported("a").unwrap();
+        assert!(journal.recover().unwrap().finished.is_empty());
+        fs::remove_dir_all(&journal.dir).unwrap();
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use wasmtime::{
+    DEFAULT_INSTANCE_LIMIT, DEFAULT_MEMORY_LIMIT, DEFAULT_TABLE_LIMIT, ResourceLimiter, Trap,
+};
+
+/// Resources a task may use, `None` means unlimited. Counts are checked by
+/// wasmtime when the component is instantiated.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TaskLimits {
+    /// max size of a single linear memory, in bytes
+    pub max_memory_bytes: Option<usize>,
+    pub max_memories: Option<usize>,
+    pub max_instances: Option<usize>,
+    pub max_tables: Option<usize>,
+    pub max_table_elements: Option<usize>,
+    /// fuel budget, roughly one unit per wasm instruction
+    pub fuel: Option<u64>,
+}
+
+impl TaskLimits {
+    /// Fill the limits not set by `self` from `default`.
+    pub fn or(self, default: &Self) -> Self {
+        Self {
+            max_memory_bytes: self.max_memory_bytes.or(default.max_memory_bytes),
+            max_memories: self.max_memories.or(default.max_memories),
+            max_instances: self.max_instances.or(default.max_instances),
+            max_tables: self.max_tables.or(default.max_tables),
+            max_table_elements: self.max_table_elements.or(default.max_table_elements),
+            fuel: self.fuel.or(default.fuel),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Error)]
+#[error("resource limit exceeded: {0}")]
+pub struct LimitExceeded(pub(crate) String);
+
+/// Whether `err` was caused by the task hitting one of its [`TaskLimits`],
+/// see [`Limiter::exceeded`].
+/// The count limit of [`Limiter::instances`], [`Limiter::tables`] or
+/// [`Limiter::memories`] that `err` reports. Wasmtime enforces those itself
+/// and only tells them apart from other errors by their message.
+pub fn count_limit_exceeded(err: &anyhow::Error) -> Option<LimitExceeded> {
+    err.chain().find_map(|cause| {
+        let msg = cause.to_string();
+        let count = msg.strip_prefix("resource limit exceeded: ")?;
+        count
+            .contains("count too high")
+            .then(|| LimitExceeded(count.to_string()))
+    })
+}
+
+pub fn is_limit_exceeded(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<LimitExceeded>().is_some()
+        || err
+            .chain()
+            .any(|cause| matches!(cause.downcast_ref::<Trap>(), Some(Trap::OutOfFuel)))
+}
+
+pub struct Limiter {
+    limits: TaskLimits,
+    exceeded: Option<LimitExceeded>,
+}
+
+impl Limiter {
+    pub const fn new(limits: TaskLimits) -> Self {
+        Self {
+            limits,
+            exceeded: None,
+        }
+    }
+
+    /// The limit the task hit, if any. The error the guest sees may have lost
+    /// it on the way, as when a trap is turned into a guest error.
+    pub const fn exceeded(&self) -> Option<&LimitExceeded> {
+        self.exceeded.as_ref()
+    }
+
+    fn exceed(&mut self, err: LimitExceeded) -> anyhow::Result<bool> {
+        self.exceeded = Some(err.clone());
+        Err(err.into())
+    }
+}
+
+impl ResourceLimiter for Limiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        match self.limits.max_memory_bytes {
+            Some(max) if desired > max => self.exceed(LimitExceeded(format!(
+                "memory of {} bytes exceeds {} bytes",
+                desired, max
+            ))),
+            _ => Ok(true),
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        match self.limits.max_table_elements {
+            Some(max) if desired > max => self.exceed(LimitExceeded(format!(
+                "table of {} elements exceeds {} elements",
+                desired, max
+            ))),
+            _ => Ok(true),
+        }
+    }
+
+    fn instances(&self) -> usize {
+        self.limits.max_instances.unwrap_or(DEFAULT_INSTANCE_LIMIT)
+    }
+
+    fn tables(&self) -> usize {
+        self.limits.max_tables.unwrap_or(DEFAULT_TABLE_LIMIT)
+    }
+
+    fn memories(&self) -> usize {
+        self.limits.max_memories.unwrap_or(DEFAULT_MEMORY_LIMIT)
+    }
+}
@@ -13,14 +13,27 @@
 // see <https://www.gnu.org/licenses/>.
 
 mod builder;
+mod cache;
+mod capabilities;
 mod data_export;
+mod distribution;
 mod engine;
+mod journal;
+mod limits;
+mod outcome;
+mod output;
+mod series;
+mod signature;
+mod sink;
+mod spool;
 mod state;
 
 #[cfg(test)]
 mod tests;
 
 use std::{
+    collections::HashMap,
+    path::Path,
     sync::{
         Arc, Mutex,
         atomic::{AtomicUsize, Ordering},
@@ -28,58 +41,348 @@ use std::{
     },
     thread,
     thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
 pub use builder::PshEngineBuilder;
+use cache::ComponentCache;
+pub use cache::ComponentCacheConfig;
+pub use capabilities::Capabilities;
 use chrono::{DateTime, Utc};
-use data_export::{Ctx, DataExportCtx, DataExporter};
-pub use engine::PshEngine;
+pub use data_export::OverflowPolicy;
+use data_export::{Ctx, DataExportCtx, DataExporter, RECOMMENDED_BUF_SIZE, TaskMetrics};
+pub use engine::{PshEngine, PshEngineHandle};
+pub use journal::{JOURNAL_DIR, Journal};
+pub use limits::TaskLimits;
+pub use outcome::{ExportCounts, HostCalls, TaskOutcome, TaskStatus};
+use output::TaskOutput;
+pub use output::{OutputLine, TaskOutputConfig};
+use serde::{Deserialize, Serialize};
+pub use series::ReservedTagPolicy;
+use series::{DEFAULT_MAX_SERIES, SeriesLimits};
+pub use signature::SignatureConfig;
+use signature::Verifier;
+use sink::{FanoutSink, FileSink, InfluxDbSink, RpcSink, Sink};
+pub use sink::{FileSinkConfig, InfluxDbConfig};
+use spool::Spool;
+pub use spool::{SpoolConfig, SpoolStatus};
 pub use state::PshState;
 
-use crate::services::rpc::RpcClient;
+use crate::{config::RpcConfig, otlp::GuestMetrics, services::rpc::RpcClient};
 
 pub struct Task {
     pub id: Option<String>,
     pub wasm_component: Vec<u8>,
     pub wasm_component_args: Vec<String>,
     pub end_time: DateTime<Utc>,
+    /// overrides the runtime wide limits
+    pub limits: TaskLimits,
+    /// overrides the runtime wide capabilities
+    pub capabilities: Option<Capabilities>,
+    pub origin: TaskOrigin,
+}
+
+/// Where a task comes from, only remote tasks are journaled and reported to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskOrigin {
+    Remote,
+    Local,
+}
+
+enum TaskState {
+    Queued,
+    Running(PshEngineHandle),
+    /// Cancelled before a worker picked it up.
+    Cancelled,
+}
+
+/// What [`TaskRuntime::tasks`] reports about a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskPhase {
+    Queued,
+    Running,
+    Cancelling,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExporterStatus {
+    pub task_id: String,
+    pub queued_items: usize,
+    pub queued_bytes: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RuntimeStatus {
+    pub concurrency: usize,
+    pub free_slots: usize,
+    pub queued: usize,
+    pub running: usize,
+    /// finished remote tasks not reported to the server yet
+    pub pending_reports: usize,
+    pub exporters: Vec<ExporterStatus>,
+    /// batches waiting for their sink to be reachable again
+    pub spools: Vec<SpoolStatus>,
+}
+
+#[derive(Default)]
+struct Shared {
+    len: AtomicUsize,
+    tasks: Mutex<HashMap<String, TaskState>>,
+    finished_tasks: Mutex<Vec<(String, TaskOutcome)>>,
+    /// data exporters of the running tasks
+    exporters: Mutex<HashMap<String, Arc<DataExporter>>>,
+    /// captured output of the running tasks
+    outputs: Mutex<HashMap<String, TaskOutput>>,
 }
 
 pub struct TaskRuntime {
     tx: Sender<Task>,
-    rx: Option<Receiver<Task>>,
-    len: Arc<AtomicUsize>,
-    finished_task_id: Arc<Mutex<Vec<String>>>,
+    /// shared by the workers, the mutex also keeps the runtime `Sync`
+    rx: Option<Arc<Mutex<Receiver<Task>>>>,
+    /// max number of tasks running at the same time
+    concurrency: usize,
+    limits: TaskLimits,
+    capabilities: Capabilities,
+    verifier: Option<Arc<Verifier>>,
+    component_cache: Option<Arc<ComponentCache>>,
+    journal: Option<Arc<Journal>>,
+    output: TaskOutputConfig,
+    /// receives the data of tasks not exported to the server
+    file_sink: Option<Arc<dyn Sink>>,
+    /// receives the line protocol of every task
+    influxdb_sink: Option<Arc<dyn Sink>>,
+    rpc_spool: Option<Arc<Spool>>,
+    spools: Vec<Arc<Spool>>,
+    data_export_overflow: Option<OverflowPolicy>,
+    data_export_flush_interval: Option<Duration>,
+    series_limits: SeriesLimits,
+    guest_metrics: Option<Arc<GuestMetrics>>,
+    shared: Arc<Shared>,
 }
 
 impl TaskRuntime {
-    pub fn new() -> Result<Self> {
+    pub fn new(cfg: &RpcConfig) -> Result<Self> {
         let (tx, rx) = channel();
+        let verifier = match &cfg.signature {
+            Some(cfg) => Some(Arc::new(Verifier::new(cfg)?)),
+            None => None,
+        };
+        let component_cache = match &cfg.component_cache {
+            Some(cfg) => Some(Arc::new(ComponentCache::new(cfg)?)),
+            None => None,
+        };
+        let file_sink = match &cfg.data_export.file {
+            Some(cfg) => Some(Arc::new(FileSink::new(cfg)?) as Arc<dyn Sink>),
+            None => None,
+        };
+        let open_spool = |sink| {
+            cfg.data_export
+                .spool
+                .as_ref()
+                .map(|cfg| Spool::open(cfg, sink).map(Arc::new))
+                .transpose()
+        };
+        if cfg.data_export.overflow.is_some() && cfg.data_export.buf_size < RECOMMENDED_BUF_SIZE {
+            tracing::warn!(
+                "data_export.buf_size of {} bytes bounds the export buffer of each task, {} is recommended",
+                cfg.data_export.buf_size,
+                RECOMMENDED_BUF_SIZE
+            );
+        }
+        let rpc_spool = open_spool("rpc")?;
+        let mut spools: Vec<_> = rpc_spool.iter().cloned().collect();
+        let influxdb_sink = match &cfg.data_export.influxdb {
+            Some(cfg) => {
+                let sink = Arc::new(InfluxDbSink::new(cfg)?);
+                match open_spool("influxdb")? {
+                    Some(spool) => {
+                        spools.push(spool.clone());
+                        Some(spool.wrap(sink)?)
+                    }
+                    None => Some(sink as Arc<dyn Sink>),
+                }
+            }
+            None => None,
+        };
 
         Ok(Self {
             tx,
-            rx: Some(rx),
-            len: Arc::new(AtomicUsize::new(0)),
-            finished_task_id: Arc::new(Mutex::new(vec![])),
+            rx: Some(Arc::new(Mutex::new(rx))),
+            concurrency: cfg.max_concurrent_tasks.unwrap_or(1).max(1),
+            limits: cfg.limits.clone(),
+            capabilities: cfg.capabilities.clone(),
+            verifier,
+            component_cache,
+            journal: None,
+            output: cfg.output.clone(),
+            file_sink,
+            influxdb_sink,
+            rpc_spool,
+            spools,
+            data_export_overflow: cfg.data_export.overflow,
+            data_export_flush_interval: cfg.data_export.flush_interval.map(Duration::from_millis),
+            series_limits: SeriesLimits {
+                max_series: cfg.data_export.max_series.unwrap_or(DEFAULT_MAX_SERIES),
+                reserved_tags: cfg.data_export.reserved_tags,
+            },
+            guest_metrics: None,
+            shared: Arc::new(Shared::default()),
         })
     }
 
+    /// Journal tasks from now on and pick up what the previous run left behind.
+    pub fn use_journal(&mut self, journal: Journal) -> Result<()> {
+        let recovered = journal.recover()?;
+        self.journal = Some(Arc::new(journal));
+
+        let mut finished_tasks = self.shared.finished_tasks.lock().unwrap();
+        finished_tasks.extend(recovered.finished);
+        drop(finished_tasks);
+        for task in recovered.tasks {
+            self.schedule(task)?;
+        }
+        Ok(())
+    }
+
+    /// Also report what tasks export as OTLP metrics, must be called before [`Self::spawn`].
+    pub fn bridge_metrics(&mut self, metrics: GuestMetrics) {
+        self.guest_metrics = Some(Arc::new(metrics));
+    }
+
     pub fn schedule(&self, task: Task) -> Result<()> {
-        self.len.fetch_add(1, Ordering::Release);
+        if let (Some(journal), TaskOrigin::Remote) = (&self.journal, task.origin) {
+            if let Err(e) = journal.accepted(&task) {
+                tracing::warn!("Failed to journal task {:?}: {:#}", task.id, e);
+            }
+        }
+        if let Some(id) = &task.id {
+            let mut tasks = self.shared.tasks.lock().unwrap();
+            tasks.insert(id.clone(), TaskState::Queued);
+        }
+        self.shared.len.fetch_add(1, Ordering::Release);
         self.tx.send(task)?;
         Ok(())
     }
 
-    pub fn is_idle(&self) -> bool {
-        let len = self.len.load(Ordering::Acquire);
-        len == 0
+    /// Cancel a queued or running task, returns `false` if the task is unknown.
+    pub fn cancel(&self, task_id: &str) -> bool {
+        let mut tasks = self.shared.tasks.lock().unwrap();
+        match tasks.get(task_id) {
+            Some(TaskState::Running(handle)) => {
+                handle.cancel();
+                // The epoch does not reach a guest blocked on a full export buffer.
+                if let Some(exporter) = self.shared.exporters.lock().unwrap().get(task_id) {
+                    exporter.abort();
+                }
+            }
+            Some(TaskState::Queued) => {
+                tasks.insert(task_id.to_string(), TaskState::Cancelled);
+            }
+            Some(TaskState::Cancelled) => {}
+            None => return false,
+        }
+        true
+    }
+
+    /// Whether the task is queued or running.
+    pub fn is_pending(&self, task_id: &str) -> bool {
+        let tasks = self.shared.tasks.lock().unwrap();
+        matches!(
+            tasks.get(task_id),
+            Some(TaskState::Queued | TaskState::Running(_))
+        )
+    }
+
+    pub fn tasks(&self) -> Vec<(String, TaskPhase)> {
+        let mut tasks: Vec<_> = self
+            .shared
+            .tasks
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, state)| {
+                let phase = match state {
+                    TaskState::Queued => TaskPhase::Queued,
+                    TaskState::Running(handle) if handle.is_cancelled() => TaskPhase::Cancelling,
+                    TaskState::Running(_) => TaskPhase::Running,
+                    TaskState::Cancelled => TaskPhase::Cancelling,
+                };
+                (id.clone(), phase)
+            })
+            .collect();
+        tasks.sort_by(|a, b| a.0.cmp(&b.0));
+        tasks
+    }
+
+    pub fn status(&self) -> RuntimeStatus {
+        let tasks = self.tasks();
+        let count = |phase| tasks.iter().filter(|(_, p)| *p == phase).count();
+        let mut exporters: Vec<_> = self
+            .shared
+            .exporters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(task_id, exporter)| {
+                let (queued_items, queued_bytes) = exporter.queued();
+                ExporterStatus {
+                    task_id: task_id.clone(),
+                    queued_items,
+                    queued_bytes,
+                }
+            })
+            .collect();
+        exporters.sort_by(|a, b| a.task_id.cmp(&b.task_id));
+
+        RuntimeStatus {
+            concurrency: self.concurrency,
+            free_slots: self.free_slots(),
+            queued: count(TaskPhase::Queued),
+            running: count(TaskPhase::Running),
+            pending_reports: self.shared.finished_tasks.lock().unwrap().len(),
+            exporters,
+            spools: self.spools.iter().map(|it| it.status()).collect(),
+        }
+    }
+
+    /// The most recent output lines of a running task and how many were dropped.
+    pub fn output(&self, task_id: &str) -> Option<(Vec<OutputLine>, u64)> {
+        let outputs = self.shared.outputs.lock().unwrap();
+        outputs.get(task_id).map(TaskOutput::lines)
     }
 
-    pub fn finished_task_id(&self) -> Option<String> {
-        self.finished_task_id.lock().unwrap().pop()
+    /// Number of tasks that can be accepted without waiting for a worker.
+    pub fn free_slots(&self) -> usize {
+        let len = self.shared.len.load(Ordering::Acquire);
+        self.concurrency.saturating_sub(len)
+    }
+
+    /// A finished task to report, once the server has all of its data. Its
+    /// last batches may still wait in the spool for the server to be back.
+    pub fn finished_task(&self) -> Option<(String, TaskOutcome)> {
+        let mut finished_tasks = self.shared.finished_tasks.lock().unwrap();
+        let spooled = |id: &str| self.rpc_spool.as_ref().is_some_and(|it| it.holds(id));
+        let i = finished_tasks.iter().rposition(|(id, _)| !spooled(id))?;
+        Some(finished_tasks.remove(i))
+    }
+
+    /// The server knows about the outcome, it can be forgotten.
+    pub fn reported(&self, task_id: &str) {
+        if let Some(journal) = &self.journal {
+            if let Err(e) = journal.reported(task_id) {
+                tracing::warn!("Failed to remove task {} from journal: {:#}", task_id, e);
+            }
+        }
     }
-    #[allow(clippy::significant_drop_tightening)]
+
+    /// Hand back an outcome that could not be reported, to retry later.
+    pub fn defer_report(&self, task_id: String, outcome: TaskOutcome) {
+        let mut finished_tasks = self.shared.finished_tasks.lock().unwrap();
+        finished_tasks.insert(0, (task_id, outcome));
+    }
+
     pub fn spawn(
         &mut self,
         rpc_client: Option<RpcClient>,
@@ -92,55 +395,306 @@ impl TaskRuntime {
             .take()
             .map_or_else(|| panic!("twice spawned"), |rx| rx);
 
-        let envs: Vec<(String, String)> = std::env::vars().collect();
+        let rpc_sink = match (rpc_client, &self.rpc_spool) {
+            (Some(rpc_client), Some(spool)) => {
+                Some(spool.wrap(Arc::new(RpcSink::new(rpc_client)))?)
+            }
+            (Some(rpc_client), None) => Some(Arc::new(RpcSink::new(rpc_client)) as Arc<dyn Sink>),
+            (None, _) => None,
+        };
+        let with_influxdb = |sink: Option<Arc<dyn Sink>>| match (sink, &self.influxdb_sink) {
+            (Some(sink), Some(influxdb)) => {
+                Some(Arc::new(FanoutSink::new(vec![sink, influxdb.clone()])) as Arc<dyn Sink>)
+            }
+            (sink, influxdb) => sink.or_else(|| influxdb.clone()),
+        };
+        let worker = Arc::new(Worker {
+            shared: self.shared.clone(),
+            envs: std::env::vars().collect(),
+            remote_sink: with_influxdb(rpc_sink),
+            local_sink: with_influxdb(self.file_sink.clone()),
+            data_export_buf_size,
+            data_export_buf_watermark,
+            data_export_overflow: self.data_export_overflow,
+            data_export_flush_interval: self.data_export_flush_interval,
+            series_limits: self.series_limits,
+            guest_metrics: self.guest_metrics.clone(),
+            instance_id,
+            limits: self.limits.clone(),
+            capabilities: self.capabilities.clone(),
+            verifier: self.verifier.clone(),
+            component_cache: self.component_cache.clone(),
+            journal: self.journal.clone(),
+            output: self.output.clone(),
+        });
+
+        let workers = (0..self.concurrency)
+            .map(|i| {
+                let rx = rx.clone();
+                let worker = worker.clone();
+                thread::Builder::new()
+                    .name(format!("psh-task-worker-{}", i))
+                    .spawn(move || {
+                        loop {
+                            // Only hold the lock while waiting for the next task, so other
+                            // workers can pick up tasks while this one is running.
+                            let task = match rx.lock() {
+                                Ok(rx) => rx.recv(),
+                                Err(_) => break,
+                            };
+                            let Ok(task) = task else {
+                                break;
+                            };
+                            worker.run(task);
+                        }
+                    })
+                    .context("Failed to spawn task worker.")
+            })
+            .collect::<Result<Vec<_>>>()?;
 
-        let len = self.len.clone();
-        let finished_task_id = self.finished_task_id.clone();
         let handle = thread::spawn(move || {
-            while let Ok(task) = rx.recv() {
-                let mut envs = envs.clone();
-                let task_time_slice = {
-                    let delta = task.end_time.timestamp_millis() - Utc::now().timestamp_millis();
-                    delta.max(0) as u64
-                };
-                envs.push(("TASK_TIME_SLICE".to_string(), task_time_slice.to_string()));
-
-                let ctx = match (rpc_client.clone(), task.id.clone()) {
-                    (Some(rpc_client), Some(task_id)) => Some(Ctx {
-                        instance_id: instance_id.clone(),
-                        exporter: Arc::new(DataExporter::new(
-                            data_export_buf_size,
-                            data_export_buf_watermark,
-                            task_id,
-                            rpc_client,
-                        )),
-                    }),
-                    _ => None,
-                };
-                let data_export_ctx = DataExportCtx { ctx };
-                let engine = PshEngineBuilder::new()
-                    .wasi_inherit_stdio()
-                    .wasi_envs(&envs)
-                    .wasi_args(&task.wasm_component_args)
-                    .allow_perf_op(true)
-                    .allow_system_op(true)
-                    .allow_data_export_op(Some(data_export_ctx.clone()))
-                    .build()
-                    .context("Failed to build PshEngine.");
-
-                match engine {
-                    Ok(o) => {
-                        let _ = o.run(&task.wasm_component, task_time_slice);
+            for worker in workers {
+                worker.join().expect("Task worker has panicked");
+            }
+        });
+
+        Ok(handle)
+    }
+}
+
+/// Everything a worker thread needs to run tasks.
+struct Worker {
+    shared: Arc<Shared>,
+    envs: Vec<(String, String)>,
+    /// for data of tasks from the server
+    remote_sink: Option<Arc<dyn Sink>>,
+    local_sink: Option<Arc<dyn Sink>>,
+    data_export_buf_size: usize,
+    data_export_buf_watermark: usize,
+    data_export_overflow: Option<OverflowPolicy>,
+    data_export_flush_interval: Option<Duration>,
+    series_limits: SeriesLimits,
+    guest_metrics: Option<Arc<GuestMetrics>>,
+    instance_id: String,
+    limits: TaskLimits,
+    capabilities: Capabilities,
+    verifier: Option<Arc<Verifier>>,
+    component_cache: Option<Arc<ComponentCache>>,
+    journal: Option<Arc<Journal>>,
+    output: TaskOutputConfig,
+}
+
+impl Worker {
+    fn run(&self, task: Task) {
+        let task_id = task.id.clone();
+        let export_id = task_id
+            .clone()
+            .unwrap_or_else(|| anonymous_export_id(&task));
+        let origin = task.origin;
+        let start = Instant::now();
+        let (status, host_calls) = self.run_task(task, &export_id);
+        let mut export = ExportCounts::default();
+        let exporter = self.shared.exporters.lock().unwrap().remove(&export_id);
+        if let Some(exporter) = exporter {
+            // The server must have all data of the task before it is reported
+            // done, what went to the spool holds back the report instead, see
+            // `TaskRuntime::finished_task`.
+            exporter.close();
+            export = exporter.counts();
+        }
+        if let Some(id) = &task_id {
+            self.shared.outputs.lock().unwrap().remove(id);
+            if let Some(guest_metrics) = &self.guest_metrics {
+                guest_metrics.finish(id);
+            }
+        }
+        let outcome = TaskOutcome {
+            status,
+            wall_time: start.elapsed(),
+            host_calls,
+            export,
+        };
+
+        let name = task_id.as_deref().unwrap_or("<local>");
+        match &outcome.status {
+            TaskStatus::Success | TaskStatus::Cancelled => tracing::info!(
+                "Task {} {} after {:?}, host calls {:?}, export {:?}",
+                name,
+                outcome.status,
+                outcome.wall_time,
+                outcome.host_calls,
+                outcome.export
+            ),
+            status => tracing::warn!(
+                "Task {} {} after {:?}, host calls {:?}, export {:?}",
+                name,
+                status,
+                outcome.wall_time,
+                outcome.host_calls,
+                outcome.export
+            ),
+        }
+
+        if let Some(id) = task_id {
+            self.shared.tasks.lock().unwrap().remove(&id);
+            if origin == TaskOrigin::Remote {
+                if let Some(journal) = &self.journal {
+                    if let Err(e) = journal.finished(&id, &outcome) {
+                        tracing::warn!("Failed to journal outcome of task {}: {:#}", id, e);
                     }
-                    Err(e) => eprintln!("{}", e),
-                };
-                if let Some(id) = task.id {
-                    finished_task_id.lock().unwrap().push(id);
                 }
-                len.fetch_sub(1, Ordering::Release);
+                let mut finished_tasks = self.shared.finished_tasks.lock().unwrap();
+                finished_tasks.push((id, outcome));
+            }
+        }
+        self.shared.len.fetch_sub(1, Ordering::Release);
+    }
+
+    fn run_task(&self, task: Task, export_id: &str) -> (TaskStatus, HostCalls) {
+        let is_cancelled = |id: &String| {
+            let tasks = self.shared.tasks.lock().unwrap();
+            matches!(tasks.get(id), Some(TaskState::Cancelled))
+        };
+        if task.id.as_ref().is_some_and(is_cancelled) {
+            return (TaskStatus::Cancelled, HostCalls::default());
+        }
+
+        // Reject before the component is even compiled.
+        if let Some(verifier) = &self.verifier {
+            if let Err(e) = verifier.verify(&task.wasm_component) {
+                return (TaskStatus::Rejected(e.to_string()), HostCalls::default());
+            }
+        }
+
+        let task_time_slice = {
+            let delta = task.end_time.timestamp_millis() - Utc::now().timestamp_millis();
+            delta.max(0) as u64
+        };
+        let mut envs = self.envs.clone();
+        envs.push(("TASK_TIME_SLICE".to_string(), task_time_slice.to_string()));
+
+        // Only tasks from the server are exported to it, the others go to files if configured.
+        let sink = match task.origin {
+            TaskOrigin::Remote => self.remote_sink.as_ref(),
+            TaskOrigin::Local => self.local_sink.as_ref(),
+        };
+        let deadline = Instant::now() + Duration::from_millis(task_time_slice);
+        let ctx = sink.map(|sink| {
+            let exporter = Arc::new(
+                DataExporter::new(
+                    match self.data_export_overflow {
+                        Some(_) => self.data_export_buf_size,
+                        None => usize::MAX,
+                    },
+                    self.data_export_buf_watermark,
+                    // Never applies to an unbounded buffer.
+                    self.data_export_overflow.unwrap_or(OverflowPolicy::Drop),
+                    self.data_export_flush_interval,
+                    export_id.to_string(),
+                    sink.clone(),
+                )
+                .with_deadline(deadline),
+            );
+            let mut exporters = self.shared.exporters.lock().unwrap();
+            exporters.insert(export_id.to_string(), exporter.clone());
+            drop(exporters);
+            Ctx {
+                instance_id: self.instance_id.clone(),
+                exporter,
             }
         });
+        let caps = task.capabilities.as_ref().unwrap_or(&self.capabilities);
+        let output = match &task.id {
+            Some(task_id) if self.output.capture => {
+                let output = TaskOutput::new(task_id.clone(), &self.output, ctx.clone());
+                let mut outputs = self.shared.outputs.lock().unwrap();
+                outputs.insert(task_id.clone(), output.clone());
+                drop(outputs);
+                Some(output)
+            }
+            _ => None,
+        };
+        let metrics = match (&self.guest_metrics, &task.id) {
+            (Some(bridge), Some(task_id)) => Some(TaskMetrics {
+                task_id: task_id.clone(),
+                bridge: bridge.clone(),
+            }),
+            _ => None,
+        };
+        let data_export_ctx = caps
+            .data_export
+            .then(|| DataExportCtx::new(ctx, metrics, self.series_limits));
+        let builder = output.as_ref().map_or_else(
+            || {
+                PshEngineBuilder::new()
+                    .wasi_inherit_stdout()
+                    .wasi_inherit_stderr()
+            },
+            |output| {
+                PshEngineBuilder::new()
+                    .wasi_stdout(output.stdout())
+                    .wasi_stderr(output.stderr())
+            },
+        );
+        let engine = builder
+            .wasi_inherit_stdin()
+            .wasi_envs(&envs)
+            .wasi_args(&task.wasm_component_args)
+            .wasi_readonly_dirs(&caps.fs)
+            .allow_perf_op(caps.perf)
+            .allow_system_interfaces(caps.system)
+            .allow_data_export_op(data_export_ctx)
+            .limits(task.limits.or(&self.limits))
+            .component_cache(self.component_cache.clone())
+            .build()
+            .context("Failed to build PshEngine.");
 
-        Ok(handle)
+        let engine = match engine {
+            Ok(o) => o,
+            Err(e) => {
+                let status = TaskStatus::BuildFailed(format!("{:#}", e));
+                return (status, HostCalls::default());
+            }
+        };
+
+        let handle = engine.handle();
+        if let Some(id) = &task.id {
+            let mut tasks = self.shared.tasks.lock().unwrap();
+            // The task may have been cancelled while the engine was being built.
+            if matches!(tasks.get(id), Some(TaskState::Cancelled)) {
+                return (TaskStatus::Cancelled, HostCalls::default());
+            }
+            tasks.insert(id.clone(), TaskState::Running(handle.clone()));
+            drop(tasks);
+            if let (Some(journal), TaskOrigin::Remote) = (&self.journal, task.origin) {
+                if let Err(e) = journal.running(id) {
+                    tracing::warn!("Failed to journal task {}: {:#}", id, e);
+                }
+            }
+        }
+
+        let result = engine.run(&task.wasm_component, task_time_slice);
+        if let Some(output) = &output {
+            output.finish();
+        }
+
+        let status = if handle.is_cancelled() {
+            TaskStatus::Cancelled
+        } else {
+            TaskStatus::from_run(result)
+        };
+        (status, handle.host_calls())
     }
 }
+
+/// Tasks run from the command line have no id, their data is exported under
+/// the file name of the component so it is found again across restarts.
+fn anonymous_export_id(task: &Task) -> String {
+    task.wasm_component_args
+        .first()
+        .and_then(|path| Path::new(path).file_stem())
+        .map_or_else(
+            || "local".to_string(),
+            |it| it.to_string_lossy().into_owned(),
+        )
+}
@@ -0,0 +1,288 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use influxdb_line_protocol::LineProtocolBuilder;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use wasmtime::{Trap, WasmBacktrace};
+use wasmtime_wasi::I32Exit;
+
+use super::limits::is_limit_exceeded;
+
+/// Where [`PshEngine::run`](super::PshEngine::run) failed before the guest started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum SetupError {
+    #[error("Failed to load component!")]
+    Load,
+    #[error("Failed to instantiate Wasi Command!")]
+    Instantiate,
+}
+
+/// How a task left the runtime.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TaskStatus {
+    Success,
+    /// The guest exited with a non-zero code.
+    Exited(i32),
+    Trapped {
+        message: String,
+        /// wasm frames, innermost first
+        backtrace: Vec<String>,
+    },
+    /// The task ran past its end time.
+    TimedOut,
+    Cancelled,
+    ResourceLimitExceeded(String),
+    /// The component signature could not be verified.
+    Rejected(String),
+    /// The engine could not be built or the component could not be compiled.
+    BuildFailed(String),
+    InstantiateFailed(String),
+}
+
+impl TaskStatus {
+    /// Classify the result of running a component that was not cancelled.
+    pub fn from_run(result: anyhow::Result<()>) -> Self {
+        let Err(e) = result else {
+            return Self::Success;
+        };
+        if let Some(exit) = e.downcast_ref::<I32Exit>() {
+            return match exit.0 {
+                0 => Self::Success,
+                code => Self::Exited(code),
+            };
+        }
+        match e.downcast_ref::<SetupError>() {
+            Some(SetupError::Load) => return Self::BuildFailed(format!("{:#}", e)),
+            Some(SetupError::Instantiate) => return Self::InstantiateFailed(format!("{:#}", e)),
+            None => {}
+        }
+        if is_limit_exceeded(&e) {
+            return Self::ResourceLimitExceeded(format!("{:#}", e));
+        }
+        if e.downcast_ref::<Trap>() == Some(&Trap::Interrupt) {
+            return Self::TimedOut;
+        }
+
+        let backtrace = e
+            .downcast_ref::<WasmBacktrace>()
+            .map(|bt| {
+                bt.frames()
+                    .iter()
+                    .map(|frame| {
+                        let module = frame.module().name().unwrap_or("<unknown>");
+                        let func = frame
+                            .func_name()
+                            .map_or_else(|| frame.func_index().to_string(), str::to_string);
+                        format!("{}!{}", module, func)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // The backtrace is kept separately, only keep the trap itself in the message.
+        let message = e
+            .downcast_ref::<Trap>()
+            .map_or_else(|| e.root_cause().to_string(), Trap::to_string);
+        Self::Trapped { message, backtrace }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Exited(_) => "exited",
+            Self::Trapped { .. } => "trapped",
+            Self::TimedOut => "timed_out",
+            Self::Cancelled => "cancelled",
+            Self::ResourceLimitExceeded(_) => "resource_limit_exceeded",
+            Self::Rejected(_) => "rejected",
+            Self::BuildFailed(_) => "build_failed",
+            Self::InstantiateFailed(_) => "instantiate_failed",
+        }
+    }
+
+    fn message(&self) -> Option<&str> {
+        match self {
+            Self::Trapped { message, .. }
+            | Self::ResourceLimitExceeded(message)
+            | Self::Rejected(message)
+            | Self::BuildFailed(message)
+            | Self::InstantiateFailed(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "exited with code {}", code),
+            Self::Trapped { message, backtrace } => {
+                write!(f, "trapped: {}", message)?;
+                for (i, frame) in backtrace.iter().enumerate() {
+                    write!(f, "\n  {:>3}: {}", i, frame)?;
+                }
+                Ok(())
+            }
+            _ => {
+                write!(f, "{}", self.name())?;
+                self.message()
+                    .map_or(Ok(()), |message| write!(f, ": {}", message))
+            }
+        }
+    }
+}
+
+/// Number of calls the guest made into each family of host ops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HostCalls {
+    pub perf: u64,
+    pub system: u64,
+    pub data_export: u64,
+}
+
+/// Items the data exporter turned away because its buffer was full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ExportCounts {
+    pub dropped: u64,
+    /// refused with an error to the guest
+    pub rejected: u64,
+}
+
+/// Counted by the linker getters, shared with [`PshEngineHandle`](super::PshEngineHandle)
+/// since the store is gone once the guest stops.
+#[derive(Debug, Default)]
+pub struct HostCallCounters {
+    pub perf: AtomicU64,
+    pub system: AtomicU64,
+    pub data_export: AtomicU64,
+}
+
+impl HostCallCounters {
+    pub fn snapshot(&self) -> HostCalls {
+        HostCalls {
+            perf: self.perf.load(Ordering::Relaxed),
+            system: self.system.load(Ordering::Relaxed),
+            data_export: self.data_export.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TaskOutcome {
+    pub status: TaskStatus,
+    pub wall_time: Duration,
+    pub host_calls: HostCalls,
+    #[serde(default)]
+    pub export: ExportCounts,
+}
+
+impl TaskOutcome {
+    /// Encode as a `psh_task_outcome` line protocol point, which is how the
+    /// outcome reaches the server since `task_done` only carries the task id.
+    pub fn to_line_protocol(&self, task_id: &str, instance_id: &str) -> Vec<u8> {
+        let lp = LineProtocolBuilder::new()
+            .measurement("psh_task_outcome")
+            .tag("task_id", task_id)
+            .tag("instance_id", instance_id)
+            .tag("status", self.status.name())
+            .field("wall_time_ms", self.wall_time.as_millis() as u64)
+            .field("host_calls_perf", self.host_calls.perf)
+            .field("host_calls_system", self.host_calls.system)
+            .field("host_calls_data_export", self.host_calls.data_export)
+            .field("export_dropped", self.export.dropped)
+            .field("export_rejected", self.export.rejected);
+        let lp = match &self.status {
+            TaskStatus::Exited(code) => lp.field("exit_code", *code as i64),
+            TaskStatus::Trapped { message, backtrace } => lp
+                .field("message", message.as_str())
+                .field("backtrace", backtrace.join("; ").as_str()),
+            status => match status.message() {
+                Some(message) => lp.field("message", message),
+                None => lp,
+            },
+        };
+        lp.close_line().build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::runtime::limits::{LimitExceeded, count_limit_exceeded};
+
+    #[test]
+    fn classify_run_result() {
+        assert_eq!(TaskStatus::from_run(Ok(())), TaskStatus::Success);
+        assert_eq!(
+            TaskStatus::from_run(Err(I32Exit(0).into())),
+            TaskStatus::Success
+        );
+        assert_eq!(
+            TaskStatus::from_run(Err(I32Exit(3).into())),
+            TaskStatus::Exited(3)
+        );
+        assert_eq!(
+            TaskStatus::from_run(Err(anyhow::Error::from(Trap::Interrupt))),
+            TaskStatus::TimedOut
+        );
+        assert_eq!(
+            TaskStatus::from_run(Err(anyhow::Error::from(Trap::OutOfFuel))).name(),
+            "resource_limit_exceeded"
+        );
+        let e = anyhow::Error::from(Trap::MemoryOutOfBounds)
+            .context(LimitExceeded("memory of 2 bytes exceeds 1 bytes".into()));
+        assert_eq!(
+            TaskStatus::from_run(Err(e)).name(),
+            "resource_limit_exceeded"
+        );
+        // as wasmtime reports a count limit on instantiation
+        let e = anyhow!("resource limit exceeded: instance count too high at 2");
+        let exceeded = count_limit_exceeded(&e).unwrap();
+        assert_eq!(
+            TaskStatus::from_run(Err(e.context(exceeded))).name(),
+            "resource_limit_exceeded"
+        );
+        assert!(count_limit_exceeded(&anyhow!("missing import")).is_none());
+        assert_eq!(
+            TaskStatus::from_run(Err(anyhow!("bad magic").context(SetupError::Load))).name(),
+            "build_failed"
+        );
+        assert_eq!(
+            TaskStatus::from_run(Err(
+                anyhow!("missing import").context(SetupError::Instantiate)
+            ))
+            .name(),
+            "instantiate_failed"
+        );
+    }
+
+    #[test]
+    fn trap_message() {
+        let e =
+            anyhow::Error::from(Trap::UnreachableCodeReached).context("Failed to run component");
+        let TaskStatus::Trapped { message, backtrace } = TaskStatus::from_run(Err(e)) else {
+            panic!("not a trap");
+        };
+        assert_eq!(message, Trap::UnreachableCodeReached.to_string());
+        assert!(backtrace.is_empty());
+    }
+}
@@ -0,0 +1,319 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use wasmtime_wasi::{HostOutputStream, StdoutStream, StreamResult, Subscribe};
+
+use super::data_export::Ctx;
+
+/// Target of the tracing events carrying guest output.
+pub const GUEST_LOG_TARGET: &str = "psh::guest";
+
+/// Most bytes accepted by a single guest write.
+const MAX_WRITE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TaskOutputConfig {
+    /// capture stdout and stderr of tasks instead of inheriting the daemon's
+    pub capture: bool,
+    /// bytes of the most recent lines kept per task, older lines are dropped
+    pub buf_size: usize,
+    /// log every line through tracing, lines go to the daemon's stdout and
+    /// stderr if neither this nor `data_export` takes them
+    pub tracing: bool,
+    /// export every line as a `psh_task_log` point, remote tasks only
+    pub data_export: bool,
+}
+
+impl Default for TaskOutputConfig {
+    fn default() -> Self {
+        Self {
+            capture: true,
+            buf_size: 64 * 1024,
+            tracing: false,
+            data_export: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OutputLine {
+    pub stream: OutputStream,
+    pub line: String,
+}
+
+impl OutputLine {
+    fn new(stream: OutputStream, line: &[u8]) -> Self {
+        Self {
+            stream,
+            line: String::from_utf8_lossy(line).into_owned(),
+        }
+    }
+}
+
+struct Buffer {
+    /// unterminated line of stdout and stderr
+    partial: [Vec<u8>; 2],
+    lines: VecDeque<OutputLine>,
+    bytes: usize,
+    dropped: u64,
+}
+
+/// Stdout and stderr of a task, split into lines and forwarded as they complete.
+#[derive(Clone)]
+pub struct TaskOutput {
+    task_id: Arc<str>,
+    capacity: usize,
+    tracing: bool,
+    export: Option<Ctx>,
+    /// write lines to the daemon's stdout and stderr
+    inherit: bool,
+    buffer: Arc<Mutex<Buffer>>,
+}
+
+impl TaskOutput {
+    pub fn new(task_id: String, cfg: &TaskOutputConfig, ctx: Option<Ctx>) -> Self {
+        let export = ctx.filter(|_| cfg.data_export);
+        Self {
+            task_id: task_id.into(),
+            capacity: cfg.buf_size.max(1),
+            tracing: cfg.tracing,
+            // The buffer goes away with the task, the lines would be lost.
+            inherit: !cfg.tracing && export.is_none(),
+            export,
+            buffer: Arc::new(Mutex::new(Buffer {
+                partial: [Vec::new(), Vec::new()],
+                lines: VecDeque::new(),
+                bytes: 0,
+                dropped: 0,
+            })),
+        }
+    }
+
+    pub fn stdout(&self) -> OutputPipe {
+        OutputPipe {
+            output: self.clone(),
+            stream: OutputStream::Stdout,
+        }
+    }
+
+    pub fn stderr(&self) -> OutputPipe {
+        OutputPipe {
+            output: self.clone(),
+            stream: OutputStream::Stderr,
+        }
+    }
+
+    /// The most recent lines and how many older ones were dropped.
+    pub fn lines(&self) -> (Vec<OutputLine>, u64) {
+        let buffer = self.buffer.lock().unwrap();
+        (buffer.lines.iter().cloned().collect(), buffer.dropped)
+    }
+
+    /// Emit what is left of unterminated lines, the guest will not write anymore.
+    pub fn finish(&self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let lines: Vec<_> = [OutputStream::Stdout, OutputStream::Stderr]
+            .into_iter()
+            .filter_map(|stream| {
+                let partial = std::mem::take(&mut buffer.partial[stream as usize]);
+                (!partial.is_empty()).then(|| OutputLine::new(stream, &partial))
+            })
+            .collect();
+        self.keep(&mut buffer, &lines);
+        drop(buffer);
+        self.forward(&lines);
+    }
+
+    fn write(&self, stream: OutputStream, bytes: &[u8]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let mut lines = Vec::new();
+        let partial = &mut buffer.partial[stream as usize];
+        for chunk in bytes.split_inclusive(|&b| b == b'\n') {
+            partial.extend_from_slice(chunk);
+            let complete = partial.last() == Some(&b'\n');
+            if complete {
+                partial.pop();
+                if partial.last() == Some(&b'\r') {
+                    partial.pop();
+                }
+            }
+            // A guest that never writes a newline must not grow the buffer forever.
+            while partial.len() > self.capacity {
+                let rest = partial.split_off(self.capacity);
+                lines.push(OutputLine::new(stream, &std::mem::replace(partial, rest)));
+            }
+            if complete {
+                lines.push(OutputLine::new(stream, &std::mem::take(partial)));
+            }
+        }
+        self.keep(&mut buffer, &lines);
+        drop(buffer);
+        self.forward(&lines);
+    }
+
+    fn keep(&self, buffer: &mut Buffer, lines: &[OutputLine]) {
+        for line in lines {
+            buffer.bytes += line.line.len();
+            buffer.lines.push_back(line.clone());
+        }
+        while buffer.bytes > self.capacity {
+            let Some(line) = buffer.lines.pop_front() else {
+                break;
+            };
+            buffer.bytes -= line.line.len();
+            buffer.dropped += 1;
+        }
+    }
+
+    fn forward(&self, lines: &[OutputLine]) {
+        for OutputLine { stream, line } in lines {
+            if self.tracing {
+                tracing::info!(
+                    target: GUEST_LOG_TARGET,
+                    task_id = &*self.task_id,
+                    stream = stream.name(),
+                    "{}",
+                    line
+                );
+            }
+            if let Some(ctx) = &self.export {
+                ctx.export_log(stream.name(), line);
+            }
+            if self.inherit {
+                // Nowhere else to report a failed write to.
+                let _ = match stream {
+                    OutputStream::Stdout => {
+                        writeln!(io::stdout().lock(), "[{}] {}", self.task_id, line)
+                    }
+                    OutputStream::Stderr => {
+                        writeln!(io::stderr().lock(), "[{}] {}", self.task_id, line)
+                    }
+                };
+            }
+        }
+    }
+}
+
+/// One of the standard output streams of a task, handed to WASI.
+#[derive(Clone)]
+pub struct OutputPipe {
+    output: TaskOutput,
+    stream: OutputStream,
+}
+
+impl StdoutStream for OutputPipe {
+    fn stream(&self) -> Box<dyn HostOutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+impl HostOutputStream for OutputPipe {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        self.output.write(self.stream, &bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(MAX_WRITE)
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl Subscribe for OutputPipe {
+    async fn ready(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(buf_size: usize) -> TaskOutput {
+        // Through tracing, which has no subscriber in tests.
+        let cfg = TaskOutputConfig {
+            buf_size,
+            tracing: true,
+            ..Default::default()
+        };
+        TaskOutput::new("42".to_string(), &cfg, None)
+    }
+
+    fn text(lines: &[OutputLine]) -> Vec<&str> {
+        lines.iter().map(|it| it.line.as_str()).collect()
+    }
+
+    #[test]
+    fn split_lines() {
+        let output = output(1024);
+        output.write(OutputStream::Stdout, b"hello ");
+        output.write(OutputStream::Stderr, b"oops\r\n");
+        output.write(OutputStream::Stdout, b"world\nsecond\nthi");
+        output.write(OutputStream::Stdout, b"rd");
+
+        let (lines, dropped) = output.lines();
+        assert_eq!(text(&lines), ["oops", "hello world", "second"]);
+        assert_eq!(lines[0].stream, OutputStream::Stderr);
+        assert_eq!(dropped, 0);
+
+        output.finish();
+        let (lines, _) = output.lines();
+        assert_eq!(text(&lines), ["oops", "hello world", "second", "third"]);
+    }
+
+    #[test]
+    fn drop_oldest_lines() {
+        let output = output(8);
+        output.write(OutputStream::Stdout, b"aaaa\nbbbb\ncccc\n");
+        let (lines, dropped) = output.lines();
+        assert_eq!(text(&lines), ["bbbb", "cccc"]);
+        assert_eq!(dropped, 1);
+
+        // Too long without a newline, split at the buffer size.
+        output.write(OutputStream::Stdout, b"0123456789");
+        let (lines, _) = output.lines();
+        assert_eq!(text(&lines), ["01234567"]);
+    }
+}
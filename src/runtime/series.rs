@@ -0,0 +1,203 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::HashSet,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use serde::Deserialize;
+
+/// Tags psh adds to what tasks export, `host` only to OTLP attributes.
+const RESERVED_TAGS: [&str; 3] = ["task_id", "instance_id", "host"];
+/// Prefix of the reserved tags of a guest with [`ReservedTagPolicy::Rename`].
+const RENAMED_PREFIX: &str = "guest_";
+pub const DEFAULT_MAX_SERIES: usize = 10000;
+/// Of measurement, tag and field names.
+const MAX_NAME_LEN: usize = 256;
+
+/// What happens to guest tags named like the ones psh adds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReservedTagPolicy {
+    /// Fail the export call of the guest.
+    #[default]
+    Reject,
+    /// Prefix the tag with `guest_`.
+    Rename,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SeriesLimits {
+    /// distinct measurement and tag set combinations of a task
+    pub max_series: usize,
+    pub reserved_tags: ReservedTagPolicy,
+}
+
+impl Default for SeriesLimits {
+    fn default() -> Self {
+        Self {
+            max_series: DEFAULT_MAX_SERIES,
+            reserved_tags: ReservedTagPolicy::Reject,
+        }
+    }
+}
+
+/// Validates what a task exports before it reaches a TSDB, and bounds the
+/// number of series it creates.
+#[derive(Debug, Default)]
+pub struct SeriesGuard {
+    limits: SeriesLimits,
+    /// hashes of the series exported so far
+    seen: HashSet<u64>,
+}
+
+fn check_name(kind: &str, name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        return Err(format!("{} name must not be empty", kind));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(format!(
+            "{} name {} is longer than {} bytes",
+            kind, name, MAX_NAME_LEN
+        ));
+    }
+    // InfluxDB keeps these for itself.
+    if name.starts_with('_') || (kind != "Measurement" && name == "time") {
+        return Err(format!("{} name {} is reserved", kind, name));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(format!("{} name {:?} has control characters", kind, name));
+    }
+    Ok(())
+}
+
+impl SeriesGuard {
+    pub fn new(limits: SeriesLimits) -> Self {
+        Self {
+            limits,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Validate a point about to be exported and count its series. Reserved
+    /// tags are renamed in place if the policy says so.
+    pub fn check<'a>(
+        &mut self,
+        name: &str,
+        tags: &mut [(String, String)],
+        fields: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), String> {
+        check_name("Measurement", name)?;
+        for (key, value) in tags.iter_mut() {
+            if RESERVED_TAGS.contains(&key.as_str()) {
+                match self.limits.reserved_tags {
+                    ReservedTagPolicy::Reject => return Err(format!("Tag {} is reserved", key)),
+                    ReservedTagPolicy::Rename => key.insert_str(0, RENAMED_PREFIX),
+                }
+            }
+            check_name("Tag", key)?;
+            // An empty tag value does not parse as line protocol.
+            if value.is_empty() || value.chars().any(char::is_control) {
+                return Err(format!(
+                    "Value of tag {} is empty or has control characters",
+                    key
+                ));
+            }
+        }
+        let mut keys: Vec<_> = tags.iter().map(|(k, _)| k.as_str()).collect();
+        keys.sort_unstable();
+        if let Some(key) = keys.windows(2).find(|it| it[0] == it[1]) {
+            return Err(format!("Tag {} is given twice", key[0]));
+        }
+        let mut fields: Vec<_> = fields.into_iter().collect();
+        fields.sort_unstable();
+        for field in &fields {
+            check_name("Field", field)?;
+        }
+        if let Some(field) = fields.windows(2).find(|it| it[0] == it[1]) {
+            return Err(format!("Field {} is given twice", field[0]));
+        }
+
+        let mut tags: Vec<_> = tags.iter().collect();
+        tags.sort_unstable();
+        let mut hasher = DefaultHasher::new();
+        (name, tags).hash(&mut hasher);
+        let series = hasher.finish();
+        if !self.seen.contains(&series) && self.seen.len() >= self.limits.max_series {
+            return Err(format!(
+                "Task has reached its limit of {} series",
+                self.limits.max_series
+            ));
+        }
+        self.seen.insert(series);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+        tags.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn validate_names() {
+        let mut guard = SeriesGuard::default();
+        let mut check = |name, t: &[(&str, &str)], fields: &[&str]| {
+            guard.check(name, &mut tags(t), fields.iter().copied())
+        };
+        assert!(check("cpu", &[("core", "0")], &["user", "system"]).is_ok());
+        assert!(check("", &[], &["value"]).is_err());
+        assert!(check("_cpu", &[], &["value"]).is_err());
+        assert!(check("cpu\n", &[], &["value"]).is_err());
+        assert!(check("cpu", &[("time", "0")], &["value"]).is_err());
+        assert!(check("cpu", &[("core", "")], &["value"]).is_err());
+        assert!(check("cpu", &[("core", "0"), ("core", "1")], &["value"]).is_err());
+        assert!(check("cpu", &[], &["value", "value"]).is_err());
+        assert!(check("cpu", &[], &["time"]).is_err());
+    }
+
+    #[test]
+    fn reserved_tags() {
+        let mut guard = SeriesGuard::default();
+        let mut t = tags(&[("task_id", "1")]);
+        assert!(guard.check("cpu", &mut t, ["value"]).is_err());
+
+        let mut guard = SeriesGuard::new(SeriesLimits {
+            reserved_tags: ReservedTagPolicy::Rename,
+            ..Default::default()
+        });
+        guard.check("cpu", &mut t, ["value"]).unwrap();
+        assert_eq!(t, tags(&[("guest_task_id", "1")]));
+    }
+
+    #[test]
+    fn limit_series() {
+        let mut guard = SeriesGuard::new(SeriesLimits {
+            max_series: 2,
+            ..Default::default()
+        });
+        let mut check = |t: &[(&str, &str)]| guard.check("cpu", &mut tags(t), ["value"]);
+        check(&[("core", "0")]).unwrap();
+        check(&[("core", "1"), ("node", "0")]).unwrap();
+        assert!(check(&[("core", "2")]).is_err());
+        // Known series are still accepted, whatever the order of their tags.
+        check(&[("node", "0"), ("core", "1")]).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use anyhow::{Context, Result, bail};
+use ring::signature::{ED25519, UnparsedPublicKey};
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Name of the custom section holding the signature.
+///
+/// It must be the last top-level section of the component, its payload is the
+/// 64 bytes ed25519 signature of every byte preceding the section.
+pub const SIGNATURE_SECTION: &str = "psh-signature";
+
+const HEADER_LEN: usize = 8;
+const CUSTOM_SECTION_ID: u8 = 0;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SignatureConfig {
+    /// hex encoded ed25519 public keys, a component signed by any of them is trusted
+    pub trusted_keys: Vec<String>,
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("component is not signed")]
+    Unsigned,
+    #[error("malformed component: {0}")]
+    Malformed(&'static str),
+    #[error("component is not signed by a trusted key")]
+    Untrusted,
+}
+
+pub struct Verifier {
+    keys: Vec<UnparsedPublicKey<Vec<u8>>>,
+}
+
+impl Verifier {
+    pub fn new(cfg: &SignatureConfig) -> Result<Self> {
+        if cfg.trusted_keys.is_empty() {
+            bail!("No trusted key configured for component signature verification");
+        }
+        let keys = cfg
+            .trusted_keys
+            .iter()
+            .map(|key| {
+                let key = hex::decode(key.trim())
+                    .with_context(|| format!("Invalid trusted key {}", key))?;
+                if key.len() != 32 {
+                    bail!(
+                        "Invalid trusted key length {}, expected 32 bytes",
+                        key.len()
+                    );
+                }
+                Ok(UnparsedPublicKey::new(&ED25519, key))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { keys })
+    }
+
+    pub fn verify(&self, wasm: &[u8]) -> Result<(), SignatureError> {
+        let (signed, signature) = split_signature(wasm)?;
+        if self
+            .keys
+            .iter()
+            .any(|key| key.verify(signed, signature).is_ok())
+        {
+            Ok(())
+        } else {
+            Err(SignatureError::Untrusted)
+        }
+    }
+}
+
+/// Split `wasm` into the signed bytes and the signature.
+fn split_signature(wasm: &[u8]) -> Result<(&[u8], &[u8]), SignatureError> {
+    if wasm.len() < HEADER_LEN || &wasm[..4] != b"\0asm" {
+        return Err(SignatureError::Malformed("bad header"));
+    }
+
+    // Sections can only be walked forward, remember where the last one starts.
+    let mut pos = HEADER_LEN;
+    let mut last = None;
+    while pos < wasm.len() {
+        let start = pos;
+        let id = wasm[pos];
+        pos += 1;
+        let size = read_u32(wasm, &mut pos)? as usize;
+        let end = pos
+            .checked_add(size)
+            .filter(|&end| end <= wasm.len())
+            .ok_or(SignatureError::Malformed("section out of bounds"))?;
+        last = Some((start, id, pos, end));
+        pos = end;
+    }
+
+    let Some((start, CUSTOM_SECTION_ID, mut pos, end)) = last else {
+        return Err(SignatureError::Unsigned);
+    };
+    let name_len = read_u32(&wasm[..end], &mut pos)? as usize;
+    let name_end = pos + name_len;
+    if name_end > end {
+        return Err(SignatureError::Malformed("section name out of bounds"));
+    }
+    if &wasm[pos..name_end] != SIGNATURE_SECTION.as_bytes() {
+        return Err(SignatureError::Unsigned);
+    }
+    Ok((&wasm[..start], &wasm[name_end..end]))
+}
+
+/// Read an unsigned LEB128 encoded u32 at `pos` and advance it.
+fn read_u32(bytes: &[u8], pos: &mut usize) -> Result<u32, SignatureError> {
+    let mut result = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes
+            .get(*pos)
+            .ok_or(SignatureError::Malformed("truncated integer"))?;
+        *pos += 1;
+        result |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(SignatureError::Malformed("integer too long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    use super::*;
+
+    // A component with a single empty custom section.
+    const COMPONENT: &[u8] = b"\0asm\x0d\0\x01\0\0\x02\x01a";
+
+    fn key_pair() -> Ed25519KeyPair {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
+    }
+
+    fn sign(wasm: &[u8], key_pair: &Ed25519KeyPair) -> Vec<u8> {
+        let signature = key_pair.sign(wasm);
+        let name = SIGNATURE_SECTION.as_bytes();
+        let mut signed = wasm.to_vec();
+        signed.push(CUSTOM_SECTION_ID);
+        signed.push((1 + name.len() + signature.as_ref().len()) as u8);
+        signed.push(name.len() as u8);
+        signed.extend_from_slice(name);
+        signed.extend_from_slice(signature.as_ref());
+        signed
+    }
+
+    fn verifier(key_pair: &Ed25519KeyPair) -> Verifier {
+        Verifier::new(&SignatureConfig {
+            trusted_keys: vec![hex::encode(key_pair.public_key())],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn accept_trusted_signature() {
+        let key_pair = key_pair();
+        let signed = sign(COMPONENT, &key_pair);
+        assert_eq!(verifier(&key_pair).verify(&signed), Ok(()));
+    }
+
+    #[test]
+    fn reject_unsigned() {
+        let verifier = verifier(&key_pair());
+        assert_eq!(verifier.verify(COMPONENT), Err(SignatureError::Unsigned));
+        assert!(matches!(
+            verifier.verify(b"not wasm"),
+            Err(SignatureError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn reject_untrusted_or_tampered() {
+        let key_pair = key_pair();
+        let verifier = verifier(&key_pair);
+
+        let signed = sign(COMPONENT, &self::key_pair());
+        assert_eq!(verifier.verify(&signed), Err(SignatureError::Untrusted));
+
+        let mut tampered = sign(COMPONENT, &key_pair);
+        tampered[HEADER_LEN + 3] = b'b';
+        assert_eq!(verifier.verify(&tampered), Err(SignatureError::Untrusted));
+    }
+
+    #[test]
+    fn reject_invalid_key() {
+        let cfg = SignatureConfig {
+            trusted_keys: vec!["abcd".to_string()],
+        };
+        assert!(Verifier::new(&cfg).is_err());
+        assert!(Verifier::new(&SignatureConfig::default()).is_err());
+    }
+}
@@ -0,0 +1,379 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use psh_proto::{Data, DataType, ExportDataReq};
+use serde::Deserialize;
+use thiserror::Error;
+use tonic::Code;
+
+use crate::{TOKIO_RUNTIME, services::rpc::RpcClient};
+
+/// Where a [`DataExporter`](super::data_export::DataExporter) ships its batches.
+pub trait Sink: Send + Sync {
+    fn export(&self, task_id: &str, data: Vec<Data>) -> Result<()>;
+}
+
+/// The sink refused the batch itself, sending it again cannot succeed.
+#[derive(Debug, Error)]
+#[error("batch rejected: {0}")]
+pub struct Rejected(pub String);
+
+pub fn is_rejected(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<Rejected>().is_some()
+}
+
+pub struct RpcSink {
+    rpc_client: RpcClient,
+}
+
+impl RpcSink {
+    pub const fn new(rpc_client: RpcClient) -> Self {
+        Self { rpc_client }
+    }
+}
+
+impl Sink for RpcSink {
+    fn export(&self, task_id: &str, data: Vec<Data>) -> Result<()> {
+        let req = ExportDataReq {
+            task_id: task_id.to_string(),
+            data,
+        };
+        let mut rpc_client = self.rpc_client.clone();
+        TOKIO_RUNTIME
+            .block_on(async move { rpc_client.export_data(req).await })
+            .map_err(|e| {
+                let code = e.downcast_ref::<tonic::Status>().map(tonic::Status::code);
+                match code {
+                    Some(
+                        Code::InvalidArgument
+                        | Code::FailedPrecondition
+                        | Code::OutOfRange
+                        | Code::Unimplemented,
+                    ) => Rejected(format!("{:#}", e)).into(),
+                    _ => e,
+                }
+            })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct FileSinkConfig {
+    pub path: String,
+}
+
+/// Writes the data of each task under `<path>/<task id>/`, a new file every hour.
+///
+/// Line protocol is appended to `<hour>.lp`, every exported file becomes
+/// `<hour>-<nanoseconds>.bin`.
+pub struct FileSink {
+    path: PathBuf,
+}
+
+impl FileSink {
+    pub fn new(cfg: &FileSinkConfig) -> Result<Self> {
+        let path = PathBuf::from(&cfg.path);
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create data directory {}", cfg.path))?;
+        Ok(Self { path })
+    }
+}
+
+impl Sink for FileSink {
+    fn export(&self, task_id: &str, data: Vec<Data>) -> Result<()> {
+        // Every run of a local task gets a `#N` suffix, keep its runs together.
+        let task = task_id
+            .rsplit_once('#')
+            .filter(|(_, n)| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+            .map_or(task_id, |(name, _)| name);
+        // Task ids come from the server or the config, keep them inside the directory.
+        let mut name = task.replace(['/', '\0'], "_");
+        if matches!(name.as_str(), "" | "." | "..") {
+            name.insert(0, '_');
+        }
+        let dir = self.path.join(name);
+        fs::create_dir_all(&dir)?;
+        let now = Utc::now();
+        let hour = now.format("%Y-%m-%dT%H");
+
+        let mut lines = Vec::new();
+        for (i, data) in data.into_iter().enumerate() {
+            match data.ty() {
+                DataType::LineProtocol => lines.extend(data.bytes),
+                DataType::File => {
+                    let nanos = now.timestamp_nanos_opt().unwrap_or_default() + i as i64;
+                    let file = dir.join(format!("{}-{}.bin", hour, nanos));
+                    fs::write(&file, data.bytes)
+                        .with_context(|| format!("Failed to write {}", file.display()))?;
+                }
+            }
+        }
+        if !lines.is_empty() {
+            let file = dir.join(format!("{}.lp", hour));
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&file)
+                .and_then(|mut f| f.write_all(&lines))
+                .with_context(|| format!("Failed to write {}", file.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Precision {
+    #[default]
+    Ns,
+    Us,
+    Ms,
+    S,
+}
+
+impl Precision {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Ns => "ns",
+            Self::Us => "us",
+            Self::Ms => "ms",
+            Self::S => "s",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct InfluxDbConfig {
+    /// base url of the server, such as `http://localhost:8086`
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    /// of the timestamps the tasks export, `ns` by default
+    #[serde(default)]
+    pub precision: Precision,
+}
+
+/// POSTs line protocol to the `/api/v2/write` endpoint of InfluxDB or a
+/// compatible TSDB. Exported files have no place there and are skipped.
+pub struct InfluxDbSink {
+    client: reqwest::Client,
+    url: String,
+    query: [(&'static str, String); 3],
+    token: String,
+}
+
+impl InfluxDbSink {
+    pub fn new(cfg: &InfluxDbConfig) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(5))
+            .timeout(Duration::from_secs(30))
+            .build()
+            .context("Failed to create InfluxDB client")?;
+        Ok(Self {
+            client,
+            url: format!("{}/api/v2/write", cfg.url.trim_end_matches('/')),
+            query: [
+                ("org", cfg.org.clone()),
+                ("bucket", cfg.bucket.clone()),
+                ("precision", cfg.precision.as_str().to_string()),
+            ],
+            token: cfg.token.clone(),
+        })
+    }
+}
+
+impl Sink for InfluxDbSink {
+    fn export(&self, _task_id: &str, data: Vec<Data>) -> Result<()> {
+        let lines: Vec<u8> = data
+            .into_iter()
+            .filter(|it| it.ty() == DataType::LineProtocol)
+            .flat_map(|it| it.bytes)
+            .collect();
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let req = self
+            .client
+            .post(&self.url)
+            .query(&self.query)
+            .header("Authorization", format!("Token {}", self.token))
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(lines);
+        TOKIO_RUNTIME.block_on(async move {
+            let resp = req.send().await.context("Failed to write to InfluxDB")?;
+            let status = resp.status();
+            if !status.is_success() {
+                let body = resp.text().await.unwrap_or_default();
+                let message = format!("InfluxDB refused the write with {}: {}", status, body);
+                // Malformed or too large, unlike auth, bucket or rate errors
+                // which may be fixed on the server side.
+                if matches!(status.as_u16(), 400 | 413 | 422) {
+                    bail!(Rejected(message));
+                }
+                bail!(message);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Hands every batch to each sink, a failing sink does not keep the others from it.
+pub struct FanoutSink {
+    sinks: Vec<Arc<dyn Sink>>,
+}
+
+impl FanoutSink {
+    pub const fn new(sinks: Vec<Arc<dyn Sink>>) -> Self {
+        Self { sinks }
+    }
+}
+
+impl Sink for FanoutSink {
+    fn export(&self, task_id: &str, data: Vec<Data>) -> Result<()> {
+        let mut result = Ok(());
+        for sink in &self.sinks {
+            if let Err(e) = sink.export(task_id, data.clone()) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_sink() {
+        let path = std::env::temp_dir().join(format!("psh-file-sink-{}", std::process::id()));
+        let sink = FileSink::new(&FileSinkConfig {
+            path: path.to_string_lossy().to_string(),
+        })
+        .unwrap();
+
+        let line = |s: &str| Data {
+            ty: DataType::LineProtocol as _,
+            bytes: s.as_bytes().to_vec(),
+        };
+        let file = Data {
+            ty: DataType::File as _,
+            bytes: vec![1, 2, 3],
+        };
+        sink.export("cpu#1", vec![line("a v=1\n"), file, line("b v=2\n")])
+            .unwrap();
+        sink.export("cpu#2", vec![line("c v=3\n")]).unwrap();
+        sink.export("../escape", vec![line("d v=4\n")]).unwrap();
+        sink.export("ver#a", vec![line("e v=5\n")]).unwrap();
+
+        let mut files: Vec<_> = fs::read_dir(path.join("cpu"))
+            .unwrap()
+            .map(|it| it.unwrap().path())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        assert_eq!(fs::read(&files[0]).unwrap(), [1, 2, 3]);
+        assert_eq!(
+            fs::read_to_string(&files[1]).unwrap(),
+            "a v=1\nb v=2\nc v=3\n"
+        );
+        assert!(path.join(".._escape").is_dir());
+        assert!(path.join("ver#a").is_dir());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn influxdb_sink() {
+        use std::{
+            io::{BufRead, BufReader, Read},
+            net::TcpListener,
+            thread,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = Vec::new();
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                head.push(std::mem::take(&mut line));
+            }
+            let len: usize = head
+                .iter()
+                .find_map(|it| {
+                    it.to_lowercase()
+                        .strip_prefix("content-length:")?
+                        .trim()
+                        .parse()
+                        .ok()
+                })
+                .unwrap();
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (head, body)
+        });
+
+        let sink = InfluxDbSink::new(&InfluxDbConfig {
+            url: format!("http://{}/", addr),
+            org: "my org".to_string(),
+            bucket: "psh".to_string(),
+            token: "secret".to_string(),
+            precision: Precision::Ms,
+        })
+        .unwrap();
+        let data = vec![
+            Data {
+                ty: DataType::LineProtocol as _,
+                bytes: b"a v=1\n".to_vec(),
+            },
+            Data {
+                ty: DataType::File as _,
+                bytes: vec![1, 2, 3],
+            },
+            Data {
+                ty: DataType::LineProtocol as _,
+                bytes: b"b v=2\n".to_vec(),
+            },
+        ];
+        sink.export("42", data).unwrap();
+
+        let (head, body) = server.join().unwrap();
+        assert_eq!(
+            head[0].trim_end(),
+            "POST /api/v2/write?org=my+org&bucket=psh&precision=ms HTTP/1.1"
+        );
+        assert!(
+            head.iter()
+                .any(|it| it.trim_end() == "authorization: Token secret")
+        );
+        assert_eq!(body, b"a v=1\nb v=2\n");
+    }
+}
@@ -0,0 +1,547 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::VecDeque,
+    fs,
+    path::PathBuf,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use prost::Message;
+use psh_proto::{Data, ExportDataReq};
+use serde::{Deserialize, Serialize};
+
+use super::sink::{Sink, is_rejected};
+
+const ENTRY_EXTENSION: &str = "batch";
+
+/// Delay before the first retry, doubled after every failure.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SpoolConfig {
+    pub path: String,
+    /// in bytes, the oldest batches are dropped above it, and the oldest
+    /// rejected ones removed
+    pub max_size: u64,
+    /// in seconds, longest wait between two retries, 300 by default
+    pub max_backoff: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SpoolStatus {
+    /// the sink the batches are for
+    pub sink: String,
+    /// batches waiting on disk
+    pub queued_batches: usize,
+    pub queued_bytes: u64,
+    /// batches written to the spool since start
+    pub spooled: u64,
+    /// export attempts of spooled batches
+    pub retried: u64,
+    /// batches lost to the size bound or corruption
+    pub dropped: u64,
+    /// batches the sink refused, moved to the rejected directory
+    pub rejected: u64,
+}
+
+struct Entry {
+    seq: u64,
+    size: u64,
+    task_id: String,
+}
+
+struct Entries {
+    /// oldest first
+    queue: VecDeque<Entry>,
+    bytes: u64,
+    next_seq: u64,
+}
+
+/// Rejected batches on disk, bound by the spool size as well.
+struct Kept {
+    /// seq and size, oldest first
+    files: VecDeque<(u64, u64)>,
+    bytes: u64,
+}
+
+/// Batches that could not be exported, kept in `<path>/<sink>/<seq>.batch`
+/// until a retry gets them through, so they survive an outage and a restart.
+/// Batches the sink rejects are not retried, they are moved to
+/// `<path>/<sink>.rejected/` to be looked at, the oldest are removed once
+/// they take more than `max_size`.
+pub struct Spool {
+    sink: &'static str,
+    dir: PathBuf,
+    rejected_dir: PathBuf,
+    max_size: u64,
+    max_backoff: Duration,
+    entries: Mutex<Entries>,
+    kept: Mutex<Kept>,
+    pushed: Condvar,
+    spooled: AtomicU64,
+    retried: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl Spool {
+    pub fn open(cfg: &SpoolConfig, sink: &'static str) -> Result<Self> {
+        let dir = PathBuf::from(&cfg.path).join(sink);
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create spool {}", dir.display()))?;
+
+        let mut queue = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != ENTRY_EXTENSION) {
+                // A write interrupted by a crash.
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            let seq = path.file_stem().and_then(|it| it.to_str()?.parse().ok());
+            let Some(seq) = seq else {
+                tracing::warn!("Ignoring unknown file {} in spool", path.display());
+                continue;
+            };
+            let bytes = fs::read(&path)?;
+            // An unreadable batch is dropped on its first retry.
+            let task_id = ExportDataReq::decode(bytes.as_slice())
+                .map(|it| it.task_id)
+                .unwrap_or_default();
+            queue.push(Entry {
+                seq,
+                size: bytes.len() as u64,
+                task_id,
+            });
+        }
+        queue.sort_unstable_by_key(|it| it.seq);
+        if !queue.is_empty() {
+            tracing::info!("{} batches left in spool {}", queue.len(), dir.display());
+        }
+
+        let rejected_dir = PathBuf::from(&cfg.path).join(format!("{}.rejected", sink));
+        let mut kept = Vec::new();
+        for entry in fs::read_dir(&rejected_dir).into_iter().flatten() {
+            let entry = entry?;
+            let seq = entry
+                .path()
+                .file_stem()
+                .and_then(|it| it.to_str()?.parse().ok());
+            if let Some(seq) = seq {
+                kept.push((seq, entry.metadata()?.len()));
+            }
+        }
+        kept.sort_unstable();
+
+        // Rejected batches are named by seq too, new ones must not replace them.
+        let next_seq = queue
+            .last()
+            .map(|it| it.seq)
+            .max(kept.last().map(|it| it.0));
+        Ok(Self {
+            sink,
+            rejected_dir,
+            dir,
+            max_size: cfg.max_size,
+            max_backoff: Duration::from_secs(cfg.max_backoff.unwrap_or(300)),
+            entries: Mutex::new(Entries {
+                bytes: queue.iter().map(|it| it.size).sum(),
+                next_seq: next_seq.map_or(0, |it| it + 1),
+                queue: queue.into(),
+            }),
+            kept: Mutex::new(Kept {
+                bytes: kept.iter().map(|it| it.1).sum(),
+                files: kept.into(),
+            }),
+            pushed: Condvar::new(),
+            spooled: AtomicU64::new(0),
+            retried: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        })
+    }
+
+    /// Put the spool in front of `sink` and start retrying what it holds.
+    pub fn wrap(self: &Arc<Self>, sink: Arc<dyn Sink>) -> Result<Arc<dyn Sink>> {
+        let spool = self.clone();
+        let retry_sink = sink.clone();
+        thread::Builder::new()
+            .name(format!("psh-spool-{}", self.sink))
+            .spawn(move || spool.retry(&*retry_sink))
+            .context("Failed to spawn spool thread.")?;
+        Ok(Arc::new(SpoolingSink {
+            sink,
+            spool: self.clone(),
+        }))
+    }
+
+    pub fn status(&self) -> SpoolStatus {
+        let entries = self.entries.lock().unwrap();
+        let (queued_batches, queued_bytes) = (entries.queue.len(), entries.bytes);
+        drop(entries);
+        SpoolStatus {
+            sink: self.sink.to_string(),
+            queued_batches,
+            queued_bytes,
+            spooled: self.spooled.load(Ordering::Relaxed),
+            retried: self.retried.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().queue.is_empty()
+    }
+
+    /// Whether batches of the task are still waiting to be exported.
+    pub fn holds(&self, task_id: &str) -> bool {
+        let entries = self.entries.lock().unwrap();
+        entries.queue.iter().any(|it| it.task_id == task_id)
+    }
+
+    fn entry_path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", seq, ENTRY_EXTENSION))
+    }
+
+    fn push(&self, task_id: &str, data: Vec<Data>) -> Result<()> {
+        let bytes = encode(task_id, data);
+        let size = bytes.len() as u64;
+
+        let mut entries = self.entries.lock().unwrap();
+        while entries.bytes + size > self.max_size {
+            let Some(oldest) = entries.queue.pop_front() else {
+                break;
+            };
+            entries.bytes -= oldest.size;
+            let _ = fs::remove_file(self.entry_path(oldest.seq));
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Spool is full, dropped the oldest batch");
+        }
+        if size > self.max_size {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!("Batch of task {} is larger than the spool", task_id);
+            return Ok(());
+        }
+
+        let seq = entries.next_seq;
+        entries.next_seq += 1;
+        // Write then rename, a crash must not leave a truncated batch behind.
+        let path = self.entry_path(seq);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, &bytes)
+            .and_then(|_| fs::rename(&tmp, &path))
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        entries.queue.push_back(Entry {
+            seq,
+            size,
+            task_id: task_id.to_string(),
+        });
+        entries.bytes += size;
+        drop(entries);
+
+        self.spooled.fetch_add(1, Ordering::Relaxed);
+        self.pushed.notify_one();
+        Ok(())
+    }
+
+    /// The oldest batch, waits for one if the spool is empty.
+    fn oldest(&self) -> u64 {
+        let entries = self.entries.lock().unwrap();
+        let entries = self
+            .pushed
+            .wait_while(entries, |it| it.queue.is_empty())
+            .unwrap();
+        entries.queue[0].seq
+    }
+
+    fn remove(&self, seq: u64) {
+        let mut entries = self.entries.lock().unwrap();
+        // It may have been dropped in the meantime.
+        let Some(i) = entries.queue.iter().position(|it| it.seq == seq) else {
+            return;
+        };
+        let entry = entries.queue.remove(i).unwrap();
+        entries.bytes -= entry.size;
+        drop(entries);
+        let _ = fs::remove_file(self.entry_path(seq));
+    }
+
+    fn rejected_path(&self, seq: u64) -> PathBuf {
+        self.rejected_dir
+            .join(format!("{:020}.{}", seq, ENTRY_EXTENSION))
+    }
+
+    /// Keep a batch the sink rejected out of the queue, `seq` only names it.
+    fn reject(&self, seq: u64, bytes: &[u8]) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        let size = bytes.len() as u64;
+
+        let mut kept = self.kept.lock().unwrap();
+        while kept.bytes + size > self.max_size {
+            let Some((oldest, oldest_size)) = kept.files.pop_front() else {
+                break;
+            };
+            kept.bytes -= oldest_size;
+            let _ = fs::remove_file(self.rejected_path(oldest));
+            tracing::warn!("Too many rejected batches, removed the oldest");
+        }
+        if size > self.max_size {
+            tracing::warn!("Rejected batch {} is larger than the spool", seq);
+            return;
+        }
+
+        let path = self.rejected_path(seq);
+        let written = fs::create_dir_all(&self.rejected_dir).and_then(|_| fs::write(&path, bytes));
+        if let Err(e) = written {
+            tracing::warn!("Failed to keep rejected batch {}: {}", path.display(), e);
+            return;
+        }
+        kept.files.push_back((seq, size));
+        kept.bytes += size;
+        drop(kept);
+    }
+
+    fn retry(&self, sink: &dyn Sink) {
+        let mut backoff = BASE_BACKOFF;
+        loop {
+            let seq = self.oldest();
+            let read = fs::read(self.entry_path(seq))
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok((ExportDataReq::decode(bytes.as_slice())?, bytes)));
+            let (req, bytes) = match read {
+                Ok(read) => read,
+                Err(e) => {
+                    tracing::warn!("Dropping unreadable batch {} from spool: {:#}", seq, e);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    self.remove(seq);
+                    continue;
+                }
+            };
+
+            self.retried.fetch_add(1, Ordering::Relaxed);
+            match sink.export(&req.task_id, req.data) {
+                Ok(()) => {
+                    self.remove(seq);
+                    backoff = BASE_BACKOFF;
+                }
+                Err(e) if is_rejected(&e) => {
+                    tracing::warn!("Spooled batch {} was rejected: {:#}", seq, e);
+                    self.reject(seq, &bytes);
+                    self.remove(seq);
+                    backoff = BASE_BACKOFF;
+                }
+                Err(e) => {
+                    tracing::debug!("Retry of spooled batch failed: {:#}", e);
+                    thread::sleep(backoff.min(self.max_backoff));
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+            }
+        }
+    }
+}
+
+/// Exports straight to the sink, or to the spool if that fails or an outage is
+/// still being caught up with, which keeps batches in order.
+struct SpoolingSink {
+    sink: Arc<dyn Sink>,
+    spool: Arc<Spool>,
+}
+
+impl Sink for SpoolingSink {
+    fn export(&self, task_id: &str, data: Vec<Data>) -> Result<()> {
+        if self.spool.is_empty() {
+            match self.sink.export(task_id, data.clone()) {
+                Ok(()) => return Ok(()),
+                Err(e) if is_rejected(&e) => {
+                    let mut entries = self.spool.entries.lock().unwrap();
+                    let seq = entries.next_seq;
+                    entries.next_seq += 1;
+                    drop(entries);
+                    self.spool.reject(seq, &encode(task_id, data));
+                    return Err(e);
+                }
+                Err(e) => tracing::warn!("Export failed, spooling the batch: {:#}", e),
+            }
+        }
+        self.spool.push(task_id, data)
+    }
+}
+
+fn encode(task_id: &str, data: Vec<Data>) -> Vec<u8> {
+    ExportDataReq {
+        task_id: task_id.to_string(),
+        data,
+    }
+    .encode_to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process, sync::atomic::AtomicBool, time::Instant};
+
+    use psh_proto::DataType;
+
+    use super::*;
+    use crate::runtime::sink::Rejected;
+
+    #[derive(Default)]
+    struct FlakySink {
+        up: AtomicBool,
+        received: Mutex<Vec<String>>,
+    }
+
+    impl Sink for FlakySink {
+        fn export(&self, task_id: &str, _: Vec<Data>) -> Result<()> {
+            anyhow::ensure!(self.up.load(Ordering::Relaxed), "down");
+            if task_id == "bad" {
+                return Err(Rejected("bad".to_string()).into());
+            }
+            self.received.lock().unwrap().push(task_id.to_string());
+            Ok(())
+        }
+    }
+
+    fn config(name: &str, max_size: u64) -> SpoolConfig {
+        let path = std::env::temp_dir().join(format!("psh-spool-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        SpoolConfig {
+            path: path.to_string_lossy().to_string(),
+            max_size,
+            max_backoff: Some(0),
+        }
+    }
+
+    fn data() -> Vec<Data> {
+        vec![Data {
+            ty: DataType::LineProtocol as _,
+            bytes: b"m v=1\n".to_vec(),
+        }]
+    }
+
+    #[test]
+    fn survive_outage_and_restart() {
+        let cfg = config("restart", 1 << 20);
+        let spool = Spool::open(&cfg, "test").unwrap();
+        spool.push("1", data()).unwrap();
+        spool.push("2", data()).unwrap();
+        assert_eq!(spool.status().queued_batches, 2);
+        drop(spool);
+
+        let spool = Arc::new(Spool::open(&cfg, "test").unwrap());
+        assert_eq!(spool.status().queued_batches, 2);
+        assert!(spool.holds("2"));
+        assert!(!spool.holds("3"));
+        let sink = Arc::new(FlakySink::default());
+        let spooling = spool.wrap(sink.clone()).unwrap();
+        // Spooled behind the older batches while they wait for a retry.
+        spooling.export("3", data()).unwrap();
+        sink.up.store(true, Ordering::Relaxed);
+
+        let start = Instant::now();
+        while !spool.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(*sink.received.lock().unwrap(), ["1", "2", "3"]);
+        let status = spool.status();
+        assert_eq!(status.spooled, 1);
+        assert!(status.retried >= 3);
+        assert_eq!(fs::read_dir(spool.dir.as_path()).unwrap().count(), 0);
+
+        fs::remove_dir_all(&cfg.path).unwrap();
+    }
+
+    #[test]
+    fn skip_rejected() {
+        let cfg = config("rejected", 1 << 20);
+        let spool = Arc::new(Spool::open(&cfg, "test").unwrap());
+        spool.push("bad", data()).unwrap();
+        spool.push("1", data()).unwrap();
+        let sink = Arc::new(FlakySink::default());
+        sink.up.store(true, Ordering::Relaxed);
+        let spooling = spool.wrap(sink.clone()).unwrap();
+
+        let start = Instant::now();
+        while !spool.is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(spooling.export("bad", data()).is_err());
+        assert_eq!(*sink.received.lock().unwrap(), ["1"]);
+        assert_eq!(spool.status().rejected, 2);
+        assert_eq!(fs::read_dir(&spool.rejected_dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&cfg.path).unwrap();
+    }
+
+    #[test]
+    fn bound_rejected() {
+        let cfg = config("bound-rejected", 100);
+        let spool = Spool::open(&cfg, "test").unwrap();
+        let bytes = encode("bad", data());
+        for seq in 0..10 {
+            spool.reject(seq, &bytes);
+        }
+        assert_eq!(spool.status().rejected, 10);
+        let kept = 100 / bytes.len();
+        let mut files: Vec<_> = fs::read_dir(&spool.rejected_dir)
+            .unwrap()
+            .map(|it| it.unwrap().path())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), kept);
+        assert_eq!(files[0], spool.rejected_path(10 - kept as u64));
+        drop(spool);
+
+        // Counted again after a restart, and not overwritten.
+        let spool = Spool::open(&cfg, "test").unwrap();
+        assert_eq!(spool.entries.lock().unwrap().next_seq, 10);
+        spool.reject(10, &bytes);
+        assert!(!spool.rejected_path(10 - kept as u64).exists());
+        assert!(spool.rejected_path(10).exists());
+
+        fs::remove_dir_all(&cfg.path).unwrap();
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let cfg = config("full", 100);
+        let spool = Spool::open(&cfg, "test").unwrap();
+        for id in 0..10 {
+            spool.push(&id.to_string(), data()).unwrap();
+        }
+        let status = spool.status();
+        assert!(status.queued_bytes <= 100);
+        assert_eq!(status.spooled, 10);
+        assert_eq!(status.dropped, 10 - status.queued_batches as u64);
+
+        let seq = spool.oldest();
+        let req = ExportDataReq::decode(fs::read(spool.entry_path(seq)).unwrap().as_slice());
+        assert_eq!(
+            req.unwrap().task_id,
+            (10 - status.queued_batches).to_string()
+        );
+
+        fs::remove_dir_all(&cfg.path).unwrap();
+    }
+}
@@ -12,12 +12,14 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+use std::sync::Arc;
+
 use host_op_perf::PerfCtx;
 use host_op_system::SysCtx;
 use wasmtime::component::ResourceTable;
 use wasmtime_wasi::{WasiCtx, WasiView};
 
-use super::DataExportCtx;
+use super::{DataExportCtx, limits::Limiter, outcome::HostCallCounters};
 
 pub struct PshState {
     #[allow(dead_code)]
@@ -27,6 +29,8 @@ pub struct PshState {
     pub perf_ctx: PerfCtx,
     pub sys_ctx: SysCtx,
     pub data_export_ctx: DataExportCtx,
+    pub limiter: Limiter,
+    pub host_calls: Arc<HostCallCounters>,
     // TODO: add more context for modules
 }
 
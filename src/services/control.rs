@@ -0,0 +1,245 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    fs::{self, Permissions},
+    io::{BufRead, BufReader, Write},
+    os::unix::{fs::PermissionsExt, net::UnixStream as StdUnixStream},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{TimeDelta, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader},
+    net::{UnixListener, UnixStream},
+    sync::watch,
+    time::timeout,
+};
+
+use crate::{
+    config::{self, LocalTaskConfig},
+    runtime::{OutputLine, RuntimeStatus, Task, TaskLimits, TaskOrigin, TaskPhase, TaskRuntime},
+    services::scheduler,
+};
+
+pub const DEFAULT_SOCKET: &str = "/run/psh.sock";
+
+/// A client has this long to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One JSON object per line, answered with a single [`Response`] line.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Request {
+    Tasks,
+    Submit {
+        path: String,
+        args: Vec<String>,
+        /// in seconds, unlimited if unset
+        time_slice: Option<u64>,
+    },
+    Cancel {
+        id: String,
+    },
+    Status,
+    Reload,
+    Logs {
+        id: String,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Response {
+    Tasks {
+        tasks: Vec<(String, TaskPhase)>,
+    },
+    Submitted {
+        id: String,
+    },
+    Cancelled,
+    Status(RuntimeStatus),
+    Reloaded {
+        local_tasks: usize,
+    },
+    Logs {
+        lines: Vec<OutputLine>,
+        dropped: u64,
+    },
+    Error {
+        message: String,
+    },
+}
+
+pub struct ControlServer<'a> {
+    pub task_rt: &'a TaskRuntime,
+    pub config_path: String,
+    pub local_tasks: watch::Sender<Vec<LocalTaskConfig>>,
+    submitted: AtomicU64,
+}
+
+impl<'a> ControlServer<'a> {
+    pub const fn new(
+        task_rt: &'a TaskRuntime,
+        config_path: String,
+        local_tasks: watch::Sender<Vec<LocalTaskConfig>>,
+    ) -> Self {
+        Self {
+            task_rt,
+            config_path,
+            local_tasks,
+            submitted: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn serve(&self, socket: &str) -> Result<()> {
+        // Left behind by a previous run that did not exit cleanly.
+        let _ = fs::remove_file(socket);
+        let listener = UnixListener::bind(socket)
+            .with_context(|| format!("Failed to bind control socket {}", socket))?;
+        fs::set_permissions(socket, Permissions::from_mode(0o600))?;
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            // Requests are cheap, serving them one at a time keeps the runtime borrowed.
+            if let Err(e) = self.handle(stream).await {
+                tracing::warn!("Control request failed: {:#}", e);
+            }
+        }
+    }
+
+    async fn handle(&self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut line = String::new();
+        timeout(
+            REQUEST_TIMEOUT,
+            AsyncBufReader::new(reader).read_line(&mut line),
+        )
+        .await
+        .context("Timed out waiting for the request")??;
+
+        let resp = match serde_json::from_str(&line) {
+            Ok(req) => self.respond(req).await.unwrap_or_else(|e| Response::Error {
+                message: format!("{:#}", e),
+            }),
+            Err(e) => Response::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+        let mut resp = serde_json::to_vec(&resp)?;
+        resp.push(b'\n');
+        writer.write_all(&resp).await?;
+        Ok(())
+    }
+
+    async fn respond(&self, req: Request) -> Result<Response> {
+        let resp = match req {
+            Request::Tasks => Response::Tasks {
+                tasks: self.task_rt.tasks(),
+            },
+            Request::Submit {
+                path,
+                args,
+                time_slice,
+            } => {
+                let n = self.submitted.fetch_add(1, Ordering::Relaxed) + 1;
+                let id = format!("ctl#{}", n);
+                let end_time = time_slice.map_or_else(
+                    || Utc.with_ymd_and_hms(3000, 1, 1, 1, 1, 1).unwrap(),
+                    |secs| Utc::now() + TimeDelta::seconds(secs as _),
+                );
+                let mut wasm_component_args = vec![path.clone()];
+                wasm_component_args.extend(args);
+                let task = Task {
+                    id: Some(id.clone()),
+                    wasm_component: tokio::fs::read(&path)
+                        .await
+                        .with_context(|| format!("Failed to read {}", path))?,
+                    wasm_component_args,
+                    end_time,
+                    limits: TaskLimits::default(),
+                    capabilities: None,
+                    origin: TaskOrigin::Local,
+                };
+                self.task_rt.schedule(task)?;
+                Response::Submitted { id }
+            }
+            Request::Cancel { id } => {
+                if !self.task_rt.cancel(&id) {
+                    bail!("Unknown task {}", id);
+                }
+                Response::Cancelled
+            }
+            Request::Status => Response::Status(self.task_rt.status()),
+            Request::Reload => {
+                // Unlike at startup, a missing config is not generated.
+                let cfg = tokio::fs::read_to_string(&self.config_path)
+                    .await
+                    .with_context(|| format!("Failed to read {}", self.config_path))?;
+                let cfg = config::parse(&cfg)?;
+                let local_tasks = cfg.daemon.tasks;
+                scheduler::validate(&local_tasks)?;
+                let len = local_tasks.len();
+                self.local_tasks.send_replace(local_tasks);
+                Response::Reloaded { local_tasks: len }
+            }
+            Request::Logs { id } => {
+                let Some((lines, dropped)) = self.task_rt.output(&id) else {
+                    bail!("No captured output of task {}, is it running?", id);
+                };
+                Response::Logs { lines, dropped }
+            }
+        };
+        Ok(resp)
+    }
+}
+
+/// Send a request to the daemon listening on `socket`.
+pub fn request(socket: impl AsRef<Path>, req: &Request) -> Result<Response> {
+    let socket = socket.as_ref();
+    let mut stream = StdUnixStream::connect(socket).with_context(|| {
+        format!(
+            "Failed to connect to {}, is the psh daemon running?",
+            socket.display()
+        )
+    })?;
+    let mut line = serde_json::to_vec(req)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_format() {
+        let req: Request = serde_json::from_str(r#"{"cmd":"cancel","id":"42"}"#).unwrap();
+        assert!(matches!(req, Request::Cancel { id } if id == "42"));
+
+        let resp = serde_json::to_string(&Response::Submitted {
+            id: "ctl#1".to_string(),
+        })
+        .unwrap();
+        assert_eq!(resp, r#"{"result":"submitted","id":"ctl#1"}"#);
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+//! Client of `proto/ext.proto`, laid out like the code tonic generates.
+
+use tonic::{
+    Request, Response, Status,
+    codec::{CompressionEncoding, ProstCodec},
+    codegen::http::uri::PathAndQuery,
+    transport::Channel,
+};
+
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct GetCancelledTasksReq {
+    #[prost(string, tag = "1")]
+    pub instance_id: String,
+}
+
+#[derive(Clone, PartialEq, Eq, prost::Message)]
+pub struct GetCancelledTasksResp {
+    #[prost(string, repeated, tag = "1")]
+    pub task_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PshExtServiceClient {
+    inner: tonic::client::Grpc<Channel>,
+}
+
+impl PshExtServiceClient {
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            inner: tonic::client::Grpc::new(channel),
+        }
+    }
+
+    pub fn send_compressed(mut self, encoding: CompressionEncoding) -> Self {
+        self.inner = self.inner.send_compressed(encoding);
+        self
+    }
+
+    pub fn accept_compressed(mut self, encoding: CompressionEncoding) -> Self {
+        self.inner = self.inner.accept_compressed(encoding);
+        self
+    }
+
+    pub async fn get_cancelled_tasks(
+        &mut self,
+        request: Request<GetCancelledTasksReq>,
+    ) -> Result<Response<GetCancelledTasksResp>, Status> {
+        self.inner
+            .ready()
+            .await
+            .map_err(|e| Status::unknown(format!("Service was not ready: {e}")))?;
+        let path = PathAndQuery::from_static("/psh.proto.ext.PshExtService/GetCancelledTasks");
+        self.inner.unary(request, path, ProstCodec::default()).await
+    }
+}
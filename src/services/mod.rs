@@ -12,5 +12,8 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+pub mod control;
+mod ext;
 pub mod host_info;
 pub mod rpc;
+pub mod scheduler;
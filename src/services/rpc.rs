@@ -12,10 +12,13 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+use std::io::Write;
+
 use anyhow::{Result, bail};
 use chrono::{TimeZone, Utc, offset::LocalResult};
+use flate2::write::GzEncoder;
 use psh_proto::{
-    ExportDataReq, GetTaskReq, HeartbeatReq, TaskDoneReq, Unit,
+    Data, DataType, ExportDataReq, GetTaskReq, HeartbeatReq, TaskDoneReq, Unit,
     psh_service_client::PshServiceClient,
 };
 use std::time::Duration;
@@ -23,17 +26,28 @@ use tokio::time::sleep;
 use tonic::Code;
 use tonic::{
     Request,
+    codec::CompressionEncoding,
+    metadata::MetadataValue,
     transport::{Channel, ClientTlsConfig, Endpoint},
 };
 
-use crate::{config::RpcConfig, runtime::Task, services::host_info::new_info_req};
+use super::ext::{GetCancelledTasksReq, PshExtServiceClient};
+use crate::{
+    config::{Compression, CompressionConfig, RpcConfig},
+    runtime::{Task, TaskLimits, TaskOrigin, TaskOutcome},
+    services::host_info::new_info_req,
+};
 
 #[derive(Clone)]
 pub struct RpcClient {
     token: String,
     client: PshServiceClient<Channel>,
+    ext: PshExtServiceClient,
+    /// Cleared once the server answers that it has no cancellation call.
+    cancel_supported: bool,
     max_retries: u32,
     base_delay: Duration,
+    compression: Option<CompressionConfig>,
 }
 
 fn into_req<T>(message: T, token: &str) -> Result<Request<T>> {
@@ -80,6 +94,26 @@ where
     }
 }
 
+/// Metadata of an `ExportDataReq` whose `DataType::File` payloads are all
+/// compressed with the algorithm it names, `gzip` or `zstd`.
+const FILE_ENCODING_KEY: &str = "psh-file-encoding";
+
+/// Compress a whole payload.
+fn compress(algorithm: Compression, level: Option<i32>, bytes: &[u8]) -> Result<Vec<u8>> {
+    let compressed = match algorithm {
+        Compression::Gzip => {
+            let level = level.map_or_else(flate2::Compression::default, |it| {
+                flate2::Compression::new(it.clamp(0, 9) as u32)
+            });
+            let mut encoder = GzEncoder::new(Vec::with_capacity(bytes.len() / 4), level);
+            encoder.write_all(bytes)?;
+            encoder.finish()?
+        }
+        Compression::Zstd => zstd::encode_all(bytes, level.unwrap_or(0))?,
+    };
+    Ok(compressed)
+}
+
 impl RpcClient {
     pub async fn new(config: &RpcConfig, token: String) -> Result<Self> {
         let ep = Endpoint::from_shared(config.addr.clone())?
@@ -98,13 +132,26 @@ impl RpcClient {
             // TLS 配置
             .tls_config(ClientTlsConfig::new().with_native_roots())?;
 
-        let client: PshServiceClient<Channel> = PshServiceClient::connect(ep).await?;
+        let channel = ep.connect().await?;
+        let mut client = PshServiceClient::new(channel.clone());
+        let mut ext = PshExtServiceClient::new(channel);
+        if let Some(compression) = &config.compression {
+            let encoding = match compression.algorithm {
+                Compression::Gzip => CompressionEncoding::Gzip,
+                Compression::Zstd => CompressionEncoding::Zstd,
+            };
+            client = client.send_compressed(encoding).accept_compressed(encoding);
+            ext = ext.send_compressed(encoding).accept_compressed(encoding);
+        }
 
         Ok(Self {
             token,
             client,
+            ext,
+            cancel_supported: true,
             max_retries: config.max_retries.unwrap_or(3),
             base_delay: config.base_delay.unwrap_or(Duration::from_secs(1)),
+            compression: config.compression.clone(),
         })
     }
 
@@ -115,8 +162,41 @@ impl RpcClient {
         Ok(())
     }
 
-    pub async fn export_data(&mut self, message: ExportDataReq) -> Result<()> {
-        let req = into_req(message, &self.token)?;
+    pub async fn export_data(&mut self, mut message: ExportDataReq) -> Result<()> {
+        let mut encoding = None;
+        if let Some(CompressionConfig {
+            algorithm,
+            min_file_size: Some(min_size),
+            level,
+        }) = self.compression.clone()
+        {
+            let is_file = |data: &Data| data.ty() == DataType::File;
+            // All files of the request or none, so that a single flag tells
+            // them apart from files that are gzip or zstd already.
+            if message
+                .data
+                .iter()
+                .any(|it| is_file(it) && it.bytes.len() >= min_size)
+            {
+                message = tokio::task::spawn_blocking(move || {
+                    for data in message.data.iter_mut().filter(|it| is_file(it)) {
+                        data.bytes = compress(algorithm, level, &data.bytes)?;
+                    }
+                    anyhow::Ok(message)
+                })
+                .await??;
+                encoding = Some(algorithm);
+            }
+        }
+        let mut req = into_req(message, &self.token)?;
+        if let Some(algorithm) = encoding {
+            let name = match algorithm {
+                Compression::Gzip => "gzip",
+                Compression::Zstd => "zstd",
+            };
+            req.metadata_mut()
+                .insert(FILE_ENCODING_KEY, MetadataValue::from_static(name));
+        }
         self.client.export_data(req).await?;
         Ok(())
     }
@@ -156,20 +236,96 @@ impl RpcClient {
             wasm_component: task.wasm,
             wasm_component_args: task.wasm_args,
             end_time,
+            limits: TaskLimits::default(),
+            capabilities: None,
+            origin: TaskOrigin::Remote,
         };
 
         Ok(Some(task))
     }
 
+    /// Ids of the tasks the server wants cancelled, none if the server does
+    /// not support cancellation.
+    pub async fn cancelled_tasks(&mut self, instance_id: String) -> Result<Vec<String>> {
+        if !self.cancel_supported {
+            return Ok(vec![]);
+        }
+        let cancelled_req = GetCancelledTasksReq { instance_id };
+        let token = &self.token;
+
+        let response = retry_with_backoff(self.max_retries, self.base_delay, async || {
+            let req = into_req(cancelled_req.clone(), token)
+                .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?;
+            self.ext.get_cancelled_tasks(req).await
+        })
+        .await;
+        match response {
+            Ok(response) => Ok(response.into_inner().task_ids),
+            Err(status) if status.code() == Code::Unimplemented => {
+                tracing::info!("Server does not support cancelling tasks");
+                self.cancel_supported = false;
+                Ok(vec![])
+            }
+            Err(status) => Err(status.into()),
+        }
+    }
+
     pub async fn task_done(&mut self, task_id: String) -> Result<()> {
         let req = into_req(TaskDoneReq { task_id }, &self.token)?;
         self.client.task_done(req).await?;
         Ok(())
     }
 
+    /// `task_done` only carries the task id, so the outcome is exported as a
+    /// `psh_task_outcome` point right before it.
+    pub async fn task_finished(
+        &mut self,
+        instance_id: &str,
+        task_id: String,
+        outcome: &TaskOutcome,
+    ) -> Result<()> {
+        let bytes = outcome.to_line_protocol(&task_id, instance_id);
+        let req = ExportDataReq {
+            task_id: task_id.clone(),
+            data: vec![Data {
+                ty: DataType::LineProtocol as _,
+                bytes,
+            }],
+        };
+        self.export_data(req).await?;
+        self.task_done(task_id).await
+    }
+
     pub async fn new_instance_id(&mut self) -> Result<String> {
         let req = into_req(Unit {}, &self.token)?;
         let resp = self.client.new_instance_id(req).await?;
         Ok(resp.into_inner().instance_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    #[test]
+    fn compress_payload() {
+        let bytes = b"cpu,host=a usage=0.5\n".repeat(100);
+
+        let gzip = compress(Compression::Gzip, Some(9), &bytes).unwrap();
+        assert!(gzip.starts_with(&[0x1f, 0x8b]));
+        let mut decoded = Vec::new();
+        GzDecoder::new(gzip.as_slice())
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, bytes);
+
+        let zstd = compress(Compression::Zstd, None, &bytes).unwrap();
+        assert!(zstd.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]));
+        assert_eq!(zstd::decode_all(zstd.as_slice()).unwrap(), bytes);
+        assert!(zstd.len() < bytes.len() / 10);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::str::FromStr;
+
+use anyhow::{Context, Error, Result, bail};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Timelike};
+
+/// A standard 5 fields cron expression: minute, hour, day of month, month and
+/// day of week. Fields accept `*`, values, ranges `a-b`, steps `*/n` or `a-b/n`
+/// and comma separated lists of those, day of week is 0-7 with both 0 and 7
+/// being Sunday.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Like cron, a restricted day of month or day of week only needs one to match.
+    any_day: bool,
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!("Invalid cron expression `{}`, expected 5 fields", s);
+        };
+        let mut weekdays = parse_field(weekday, 0, 7).context("Invalid day of week")?;
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59).context("Invalid minute")?,
+            hours: parse_field(hour, 0, 23).context("Invalid hour")?,
+            days: parse_field(day, 1, 31).context("Invalid day of month")?,
+            months: parse_field(month, 1, 12).context("Invalid month")?,
+            weekdays,
+            any_day: day != "*" && weekday != "*",
+        })
+    }
+}
+
+impl Cron {
+    /// First matching minute strictly after `after`.
+    pub fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let mut t = after
+            .with_second(0)?
+            .with_nanosecond(0)?
+            .checked_add_signed(Duration::minutes(1))?;
+        // Every combination repeats within a few years, give up after that.
+        let limit = t.clone().checked_add_signed(Duration::days(366 * 8))?;
+        while t < limit {
+            if !matches(self.months, t.month()) {
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    month => (t.year(), month + 1),
+                };
+                t = start_of_day(&t.timezone(), year, month, 1)?;
+            } else if !self.matches_day(&t) {
+                let next = t.date_naive().succ_opt()?;
+                t = start_of_day(&t.timezone(), next.year(), next.month(), next.day())?;
+            } else if !matches(self.hours, t.hour()) {
+                t = t.with_minute(0)?.checked_add_signed(Duration::hours(1))?;
+            } else if !matches(self.minutes, t.minute()) {
+                t = t.checked_add_signed(Duration::minutes(1))?;
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+
+    fn matches_day<Tz: TimeZone>(&self, t: &DateTime<Tz>) -> bool {
+        let day = matches(self.days, t.day());
+        let weekday = matches(self.weekdays, t.weekday().num_days_from_sunday());
+        if self.any_day {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+const fn matches(field: u64, value: u32) -> bool {
+    field & (1 << value) != 0
+}
+
+fn start_of_day<Tz: TimeZone>(tz: &Tz, year: i32, month: u32, day: u32) -> Option<DateTime<Tz>> {
+    // Midnight may not exist on DST changes, the earliest valid time is fine.
+    tz.with_ymd_and_hms(year, month, day, 0, 0, 0)
+        .earliest()
+        .or_else(|| tz.with_ymd_and_hms(year, month, day, 1, 0, 0).earliest())
+}
+
+/// Parse a field into a bit set of the values it matches.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>()?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            bail!("Step of `{}` must be positive", part);
+        }
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (start.parse()?, end.parse()?),
+                // `a/n` means from `a` to the end
+                None if step > 1 => (range.parse()?, max),
+                None => {
+                    let value = range.parse()?;
+                    (value, value)
+                }
+            },
+        };
+        if start < min || end > max || start > end {
+            bail!("`{}` is out of range {}-{}", part, min, max);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn next(cron: &str, after: &str) -> String {
+        let after = DateTime::parse_from_rfc3339(after)
+            .unwrap()
+            .with_timezone(&Utc);
+        let cron: Cron = cron.parse().unwrap();
+        cron.next_after(&after).unwrap().to_rfc3339()
+    }
+
+    #[test]
+    fn parse() {
+        assert!("* * * * *".parse::<Cron>().is_ok());
+        assert!("*/15 0-6,22 1 */2 1-5".parse::<Cron>().is_ok());
+        assert!("* * * *".parse::<Cron>().is_err());
+        assert!("60 * * * *".parse::<Cron>().is_err());
+        assert!("* * 0 * *".parse::<Cron>().is_err());
+        assert!("*/0 * * * *".parse::<Cron>().is_err());
+        assert!("5-1 * * * *".parse::<Cron>().is_err());
+        assert!("a * * * *".parse::<Cron>().is_err());
+    }
+
+    #[test]
+    fn next_minute() {
+        assert_eq!(
+            next("* * * * *", "2024-01-01T00:00:30Z"),
+            "2024-01-01T00:01:00+00:00"
+        );
+        assert_eq!(
+            next("*/15 * * * *", "2024-01-01T00:15:00Z"),
+            "2024-01-01T00:30:00+00:00"
+        );
+        assert_eq!(
+            next("5/20 * * * *", "2024-01-01T00:46:00Z"),
+            "2024-01-01T01:05:00+00:00"
+        );
+    }
+
+    #[test]
+    fn next_day_and_month() {
+        assert_eq!(
+            next("30 2 * * *", "2024-01-01T03:00:00Z"),
+            "2024-01-02T02:30:00+00:00"
+        );
+        assert_eq!(
+            next("0 0 1 */3 *", "2024-02-10T00:00:00Z"),
+            "2024-04-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2024-03-01T00:00:00Z"),
+            "2028-02-29T00:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 0 1 1 *", "2024-12-31T23:59:00Z"),
+            "2025-01-01T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn weekday() {
+        // 2024-01-01 is a Monday
+        assert_eq!(
+            next("0 9 * * 0", "2024-01-01T00:00:00Z"),
+            "2024-01-07T09:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 9 * * 7", "2024-01-01T00:00:00Z"),
+            "2024-01-07T09:00:00+00:00"
+        );
+        // either the day of month or the day of week
+        assert_eq!(
+            next("0 0 15 * 3", "2024-01-01T00:00:00Z"),
+            "2024-01-03T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn never() {
+        let cron: Cron = "0 0 31 2 *".parse().unwrap();
+        assert_eq!(cron.next_after(&Utc::now()), None);
+    }
+}
@@ -0,0 +1,199 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+mod cron;
+
+use std::{collections::HashSet, future::pending, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use cron::Cron;
+use tokio::sync::watch;
+
+use crate::{
+    config::LocalTaskConfig,
+    runtime::{Task, TaskOrigin, TaskRuntime},
+};
+
+enum Schedule {
+    Interval(TimeDelta),
+    Cron(Cron),
+}
+
+impl Schedule {
+    fn next_after(&self, after: &DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Self::Interval(interval) => after.checked_add_signed(*interval),
+            Self::Cron(cron) => cron.next_after(after),
+        }
+    }
+}
+
+struct Entry {
+    cfg: LocalTaskConfig,
+    schedule: Schedule,
+    next: Option<DateTime<Local>>,
+    runs: u64,
+    last_id: Option<String>,
+}
+
+impl Entry {
+    fn new(cfg: LocalTaskConfig) -> Result<Self> {
+        let schedule = match (cfg.interval, &cfg.cron) {
+            (Some(0), None) => bail!("Interval of local task {} must be positive", cfg.name),
+            (Some(interval), None) => Schedule::Interval(TimeDelta::seconds(interval as _)),
+            (None, Some(cron)) => Schedule::Cron(
+                cron.parse()
+                    .with_context(|| format!("Invalid schedule of local task {}", cfg.name))?,
+            ),
+            _ => bail!(
+                "Local task {} needs exactly one of `interval` or `cron`",
+                cfg.name
+            ),
+        };
+        // Intervals start right away, cron expressions wait for their first match.
+        let now = Local::now();
+        let next = match &schedule {
+            Schedule::Interval(_) => Some(now),
+            Schedule::Cron(cron) => cron.next_after(&now),
+        };
+        Ok(Self {
+            cfg,
+            schedule,
+            next,
+            runs: 0,
+            last_id: None,
+        })
+    }
+
+    async fn run(&mut self, task_rt: &TaskRuntime) -> Result<()> {
+        let name = &self.cfg.name;
+        if let Some(id) = &self.last_id {
+            if task_rt.is_pending(id) {
+                tracing::warn!(
+                    "Skip local task {}, its previous run has not finished",
+                    name
+                );
+                return Ok(());
+            }
+        }
+
+        let wasm_component = tokio::fs::read(&self.cfg.path)
+            .await
+            .with_context(|| format!("Failed to read {}", self.cfg.path))?;
+        let mut wasm_component_args = Vec::with_capacity(self.cfg.args.len() + 1);
+        wasm_component_args.push(self.cfg.path.clone());
+        wasm_component_args.extend(self.cfg.args.iter().cloned());
+
+        self.runs += 1;
+        let id = format!("{}#{}", name, self.runs);
+        let task = Task {
+            id: Some(id.clone()),
+            wasm_component,
+            wasm_component_args,
+            end_time: Utc::now() + TimeDelta::seconds(self.cfg.time_slice as _),
+            limits: self.cfg.limits.clone(),
+            capabilities: self.cfg.capabilities.clone(),
+            origin: TaskOrigin::Local,
+        };
+        task_rt.schedule(task)?;
+        self.last_id = Some(id);
+        Ok(())
+    }
+}
+
+fn build(tasks: &[LocalTaskConfig], prev: &[Entry]) -> Result<Vec<Entry>> {
+    let mut names = HashSet::new();
+    tasks
+        .iter()
+        .map(|cfg| {
+            if !names.insert(&cfg.name) {
+                bail!("Duplicated local task {}", cfg.name);
+            }
+            let mut entry = Entry::new(cfg.clone())?;
+            // Keep counting runs, ids must not collide with a run still in flight.
+            if let Some(prev) = prev.iter().find(|e| e.cfg.name == cfg.name) {
+                entry.runs = prev.runs;
+                entry.last_id = prev.last_id.clone();
+                if prev.cfg.interval == cfg.interval && prev.cfg.cron == cfg.cron {
+                    entry.next = prev.next;
+                }
+            }
+            Ok(entry)
+        })
+        .collect()
+}
+
+pub fn validate(tasks: &[LocalTaskConfig]) -> Result<()> {
+    build(tasks, &[]).map(|_| ())
+}
+
+/// Run the local tasks on their schedule, picking up new ones from `tasks`.
+///
+/// Returns once there is nothing scheduled and `tasks` can no longer change.
+pub async fn run_local_tasks(
+    mut tasks: watch::Receiver<Vec<LocalTaskConfig>>,
+    task_rt: &TaskRuntime,
+) -> Result<()> {
+    let mut entries = build(&tasks.borrow_and_update(), &[])?;
+    let mut reloadable = true;
+
+    loop {
+        let now = Local::now();
+        for entry in entries.iter_mut() {
+            let Some(next) = entry.next else {
+                continue;
+            };
+            if next > now {
+                continue;
+            }
+            if let Err(e) = entry.run(task_rt).await {
+                tracing::warn!("Failed to run local task {}: {:#}", entry.cfg.name, e);
+            }
+            entry.next = entry.schedule.next_after(&now);
+        }
+
+        let next = entries.iter().filter_map(|e| e.next).min();
+        if next.is_none() && !reloadable {
+            return Ok(());
+        }
+        let sleep = async {
+            match next {
+                Some(next) => {
+                    let delay = (next - Local::now()).to_std().unwrap_or(Duration::ZERO);
+                    tokio::time::sleep(delay).await;
+                }
+                None => pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = sleep => {}
+            changed = tasks.changed(), if reloadable => {
+                if changed.is_err() {
+                    reloadable = false;
+                    continue;
+                }
+                let cfgs = tasks.borrow_and_update().clone();
+                match build(&cfgs, &entries) {
+                    Ok(new) => {
+                        tracing::info!("Reloaded {} local tasks", new.len());
+                        entries = new;
+                    }
+                    Err(e) => tracing::warn!("Failed to reload local tasks: {:#}", e),
+                }
+            }
+        }
+    }
+}